@@ -0,0 +1,152 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use crate::network::{Method, Request, Response};
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Days since 1970-01-01 for a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The inverse of `days_from_civil`: `(year, month, day)` for days since 1970-01-01.
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Formats Unix seconds as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn format_http_date(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        DAYS[days.rem_euclid(7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Parses the IMF-fixdate form we send ourselves, which is all modern clients
+/// echo back in `If-Modified-Since`.
+pub fn parse_http_date(value: &str) -> Option<u64> {
+    let mut parts = value.split_whitespace();
+    let _weekday = parts.next()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|m| *m == month)? as i64 + 1;
+    let year: i64 = parts.next()?.parse().ok()?;
+    let time: Vec<u64> = parts.next()?.split(':').map(|n| n.parse().ok()).collect::<Option<_>>()?;
+    if parts.next()? != "GMT" || time.len() != 3 || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    Some(days * 86400 + time[0] * 3600 + time[1] * 60 + time[2])
+}
+
+/// A strong validator for `body`: the same bytes always get the same tag.
+pub fn etag(body: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    format!("\"{:016x}{:08x}\"", hasher.finish(), body.len())
+}
+
+/// Weak comparison, as `If-None-Match` uses: `W/"x"` matches `"x"`.
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match.trim() == "*" || if_none_match.split(',').any(|tag| opaque(tag) == opaque(etag))
+}
+
+/// Adds `ETag` and, if known, `Last-Modified` to a successful GET or HEAD
+/// response, and swaps it for a bodiless 304 when the client's copy is current.
+/// `If-None-Match` wins over `If-Modified-Since` when both are sent.
+pub fn conditional(request: &Request, response: Response, last_modified: Option<u64>) -> Response {
+    let etag = etag(response.get_body());
+    conditional_with(request, response, etag, last_modified)
+}
+
+/// `conditional` with an ETag worked out by the caller, e.g. from a file's
+/// size and modification time rather than its bytes.
+pub fn conditional_with(request: &Request, response: Response, etag: String, last_modified: Option<u64>) -> Response {
+    if !matches!(request.get_method(), Method::GET | Method::HEAD) || response.get_status() != 200 {
+        return response;
+    }
+
+    let last_modified = last_modified.map(format_http_date);
+
+    let fresh = match (request.get_header("If-None-Match"), request.get_header("If-Modified-Since")) {
+        (Some(tags), _) => etag_matches(tags, &etag),
+        (None, Some(since)) => match (parse_http_date(since), last_modified.as_deref().and_then(parse_http_date)) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        },
+        (None, None) => false,
+    };
+
+    let mut response = if fresh { Response::empty(304) } else { response };
+    response.set_header("ETag", &etag);
+    if let Some(last_modified) = &last_modified {
+        response.set_header("Last-Modified", last_modified);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(headers: &str) -> Request {
+        let raw = format!("GET /problem/1 HTTP/1.1\r\n{}\r\n", headers);
+        Request::from_raw(&raw)
+    }
+
+    #[test]
+    fn formats_and_parses_http_dates() {
+        assert_eq!(format_http_date(784111777), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(format_http_date(951782400), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784111777));
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+    }
+
+    #[test]
+    fn answers_304_when_the_client_is_current() {
+        let body = || Response::text(200, "problem");
+        let tag = etag(body().get_body());
+
+        let response = conditional(&get(&format!("If-None-Match: \"x\", W/{}\r\n", tag)), body(), None);
+        assert_eq!(response.get_status(), 304);
+        assert!(response.get_body().is_empty());
+        assert_eq!(response.get_header("ETag"), Some(tag.as_str()));
+
+        let response = conditional(&get("If-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n"), body(), Some(784111777));
+        assert_eq!(response.get_status(), 304);
+
+        let response = conditional(&get("If-Modified-Since: Sun, 06 Nov 1994 08:49:36 GMT\r\n"), body(), Some(784111777));
+        assert_eq!(response.get_status(), 200);
+
+        // A stale tag means modified, whatever the date says.
+        let headers = "If-None-Match: \"old\"\r\nIf-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n";
+        assert_eq!(conditional(&get(headers), body(), Some(784111777)).get_status(), 200);
+    }
+}
@@ -0,0 +1,153 @@
+use std::io::Write;
+use flate2::Compression;
+use flate2::write::{GzEncoder, ZlibEncoder};
+
+use crate::network::Response;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn encode(&self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            },
+            // HTTP's "deflate" is the zlib format, not a raw deflate stream.
+            Encoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            },
+        }
+    }
+}
+
+/// Picks the encoding the client likes best from an `Accept-Encoding` value,
+/// preferring gzip on a tie. `None` means send the body as is.
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let mut gzip = None;
+    let mut deflate = None;
+    let mut wildcard = None;
+
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let quality = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        match name.as_str() {
+            "gzip" | "x-gzip" => gzip = Some(quality),
+            "deflate" => deflate = Some(quality),
+            "*" => wildcard = Some(quality),
+            _ => {},
+        }
+    }
+
+    // An explicit entry wins over the wildcard, so `*, gzip;q=0` rules out gzip.
+    let gzip = gzip.or(wildcard).unwrap_or(0.0);
+    let deflate = deflate.or(wildcard).unwrap_or(0.0);
+    if gzip > 0.0 && gzip >= deflate {
+        Some(Encoding::Gzip)
+    } else if deflate > 0.0 {
+        Some(Encoding::Deflate)
+    } else {
+        None
+    }
+}
+
+/// Whether a body of this type is worth compressing. Images, archives and
+/// the like are compressed already.
+fn compressible(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json" | "application/javascript" | "application/xml" | "image/svg+xml" | "application/wasm"
+        )
+}
+
+/// Compresses `response` for a client that sent `accept_encoding`, if the body
+/// is big enough and of a type that shrinks. Every response that could have been
+/// compressed gets `Vary: Accept-Encoding`, so caches keep the variants apart.
+pub fn compress(response: &mut Response, accept_encoding: Option<&str>, min_size: usize) {
+    if response.get_header("Content-Encoding").is_some()
+        || response.is_stream()
+        || matches!(response.get_status(), 204 | 206 | 304)
+        || response.get_body().len() < min_size
+        || !response.get_header("Content-Type").is_some_and(compressible)
+    {
+        return;
+    }
+
+    response.add_vary("Accept-Encoding");
+
+    let Some(encoding) = accept_encoding.and_then(negotiate) else { return };
+    let Ok(encoded) = encoding.encode(response.get_body()) else { return };
+    // Nothing gained, e.g. the body was random data: send it as is.
+    if encoded.len() >= response.get_body().len() {
+        return;
+    }
+
+    response.set_body(encoded);
+    response.set_header("Content-Encoding", encoding.as_str());
+    // The encoded bytes differ from the ones a strong ETag vouches for.
+    if let Some(etag) = response.get_header("ETag").filter(|tag| !tag.starts_with("W/")) {
+        let weak = format!("W/{}", etag);
+        response.set_header("ETag", &weak);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use flate2::read::GzDecoder;
+    use serde_json::json;
+
+    #[test]
+    fn negotiates_by_quality() {
+        assert_eq!(negotiate("gzip, deflate, br"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("deflate, gzip;q=0.5"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("gzip;q=0, deflate;q=0.1"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("*;q=0.5, gzip;q=0"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(negotiate(""), None);
+    }
+
+    #[test]
+    fn compresses_large_json_only() {
+        let problems: Vec<_> = (0..100).map(|i| json!({ "id": i, "title": "Two sum" })).collect();
+        let mut response = Response::ok(json!({ "problems": problems }));
+        let original = response.get_body().to_vec();
+
+        compress(&mut response, Some("gzip"), 1024);
+        assert_eq!(response.get_header("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.get_header("Vary"), Some("Accept-Encoding"));
+
+        let mut decoded = Vec::new();
+        GzDecoder::new(response.get_body()).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, original);
+
+        let mut small = Response::ok(json!({ "message": "hi" }));
+        compress(&mut small, Some("gzip"), 1024);
+        assert!(small.get_header("Content-Encoding").is_none());
+        assert!(small.get_header("Vary").is_none());
+    }
+}
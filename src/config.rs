@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::logging::{Format, Level};
+use crate::rate_limit::Rate;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+const KEYS: &[&str] = &[
+    "BIND_ADDR",
+    "PORT",
+    "UNIX_SOCKET",
+    "LISTEN_BACKLOG",
+    "WORKER_COUNT",
+    "QUEUE_LIMIT",
+    "MAX_SESSIONS",
+    "MAX_STREAMS",
+    "MAX_BODY_SIZE",
+    "MAX_HEADER_SIZE",
+    "MAX_HEADER_COUNT",
+    "KEEP_ALIVE_TIMEOUT",
+    "HEADER_TIMEOUT",
+    "BODY_TIMEOUT",
+    "WRITE_TIMEOUT",
+    "SHUTDOWN_TIMEOUT",
+    "COMPRESSION_MIN_SIZE",
+    "STATIC_DIR",
+    "STATIC_PREFIX",
+    "STATIC_MAX_AGE",
+    "ALLOWED_ORIGINS",
+    "CORS_ALLOWED_METHODS",
+    "CORS_ALLOWED_HEADERS",
+    "CORS_ALLOW_CREDENTIALS",
+    "CORS_MAX_AGE",
+    "LOG_LEVEL",
+    "LOG_FORMAT",
+    "TRUST_PROXY",
+    "PROXY_HOPS",
+    "RATE_LIMIT_LOGIN",
+    "RATE_LIMIT_IDE",
+    "RATE_LIMIT_SOLVE",
+    "METRICS_TOKEN",
+];
+
+/// Where the server accepts connections.
+pub enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listen::Tcp(addr) => write!(f, "{}", addr),
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+pub struct Config {
+    pub listen: Listen,
+    pub backlog: i32,
+    pub workers: usize,
+    pub queue_limit: usize,
+    /// Interactive sessions open at once, each holding a thread and a sandbox run.
+    pub max_sessions: usize,
+    /// Event streams open at once, each holding a thread.
+    pub max_streams: usize,
+    pub max_body_size: usize,
+    pub max_header_size: usize,
+    pub max_header_count: usize,
+    pub keep_alive_timeout: u64,
+    /// Seconds a client gets to send a whole request head, from its first byte.
+    pub header_timeout: u64,
+    /// Seconds a client gets to send the body once the head is in.
+    pub body_timeout: u64,
+    /// Seconds a response may sit unwritten before we give up on the client.
+    pub write_timeout: u64,
+    pub shutdown_timeout: u64,
+    /// Bodies smaller than this many bytes are never compressed.
+    pub compression_min_size: usize,
+    /// Directory served as static files (e.g. the frontend build), if any.
+    pub static_dir: Option<PathBuf>,
+    pub static_prefix: String,
+    /// `Cache-Control: max-age` in seconds for static files other than HTML.
+    pub static_max_age: u64,
+    /// Comma-separated CORS allow-lists, see `middleware::Cors`.
+    pub cors_allowed_origins: String,
+    pub cors_allowed_methods: String,
+    pub cors_allowed_headers: String,
+    pub cors_allow_credentials: bool,
+    pub cors_max_age: u64,
+    pub log_level: Level,
+    pub log_format: Format,
+    /// Take the client address from `X-Forwarded-For`, for when we sit behind a proxy.
+    pub trust_proxy: bool,
+    /// How many proxies we sit behind, counting from us, when trusting them.
+    pub proxy_hops: usize,
+    /// Per-client limits such as `10/min`, or `off`.
+    pub rate_limit_login: Rate,
+    pub rate_limit_ide: Rate,
+    pub rate_limit_solve: Rate,
+    /// Bearer token `/metrics` asks for; open to anyone when unset.
+    pub metrics_token: Option<String>,
+}
+
+/// Settings from the command line take precedence over the environment.
+/// `--listen-backlog 512` and `--listen-backlog=512` both set `LISTEN_BACKLOG`.
+struct Settings {
+    overrides: HashMap<String, String>,
+}
+
+impl Settings {
+    fn from_args() -> Settings {
+        let mut overrides = HashMap::new();
+        let mut args = env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            let flag = arg
+                .strip_prefix("--")
+                .unwrap_or_else(|| panic!("Unexpected argument '{}'", arg));
+            let (name, value) = match flag.split_once('=') {
+                Some((name, value)) => (name.to_string(), value.to_string()),
+                None => {
+                    let value = args
+                        .next()
+                        .unwrap_or_else(|| panic!("Missing value for '--{}'", flag));
+                    (flag.to_string(), value)
+                },
+            };
+
+            let key = name.replace('-', "_").to_uppercase();
+            if !KEYS.contains(&key.as_str()) {
+                panic!("Unknown option '--{}'", name);
+            }
+            overrides.insert(key, value);
+        }
+
+        Settings { overrides }
+    }
+
+    fn raw(&self, key: &str) -> Option<String> {
+        self.overrides
+            .get(key)
+            .cloned()
+            .or_else(|| env::var(key).ok())
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+    }
+
+    fn get<T: std::str::FromStr>(&self, key: &str, default: T) -> T {
+        match self.raw(key) {
+            Some(value) => value
+                .parse()
+                .unwrap_or_else(|_| panic!("Invalid value '{}' for {}", value, key)),
+            None => default,
+        }
+    }
+}
+
+pub fn init_config() {
+    let settings = Settings::from_args();
+    let default_workers = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(4);
+
+    let listen = match settings.raw("UNIX_SOCKET") {
+        Some(path) => Listen::Unix(PathBuf::from(path)),
+        None => {
+            let ip: IpAddr = settings.get("BIND_ADDR", IpAddr::from([0, 0, 0, 0]));
+            Listen::Tcp(SocketAddr::new(ip, settings.get("PORT", 8080)))
+        },
+    };
+
+    let config = Config {
+        listen,
+        backlog: settings.get("LISTEN_BACKLOG", 128),
+        workers: settings.get("WORKER_COUNT", default_workers).max(1),
+        queue_limit: settings.get("QUEUE_LIMIT", 64),
+        max_sessions: settings.get("MAX_SESSIONS", 32),
+        max_streams: settings.get("MAX_STREAMS", 64),
+        max_body_size: settings.get("MAX_BODY_SIZE", 1024 * 1024),
+        max_header_size: settings.get("MAX_HEADER_SIZE", 8 * 1024),
+        max_header_count: settings.get("MAX_HEADER_COUNT", 100),
+        keep_alive_timeout: settings.get("KEEP_ALIVE_TIMEOUT", 5),
+        header_timeout: settings.get("HEADER_TIMEOUT", 10),
+        body_timeout: settings.get("BODY_TIMEOUT", 30),
+        write_timeout: settings.get("WRITE_TIMEOUT", 30),
+        shutdown_timeout: settings.get("SHUTDOWN_TIMEOUT", 30),
+        compression_min_size: settings.get("COMPRESSION_MIN_SIZE", 1024),
+        static_dir: settings.raw("STATIC_DIR").map(PathBuf::from),
+        static_prefix: settings.get("STATIC_PREFIX", "/".to_string()),
+        static_max_age: settings.get("STATIC_MAX_AGE", 3600),
+        cors_allowed_origins: settings.get("ALLOWED_ORIGINS", "http://localhost:3000".to_string()),
+        cors_allowed_methods: settings.get("CORS_ALLOWED_METHODS", "GET, POST, PUT, DELETE, OPTIONS".to_string()),
+        cors_allowed_headers: settings.get("CORS_ALLOWED_HEADERS", "Content-Type, Authorization".to_string()),
+        cors_allow_credentials: settings.get("CORS_ALLOW_CREDENTIALS", true),
+        cors_max_age: settings.get("CORS_MAX_AGE", 600),
+        log_level: settings.get("LOG_LEVEL", Level::Info),
+        log_format: settings.get("LOG_FORMAT", Format::Common),
+        trust_proxy: settings.get("TRUST_PROXY", false),
+        proxy_hops: settings.get("PROXY_HOPS", 1),
+        rate_limit_login: settings.get("RATE_LIMIT_LOGIN", Rate { requests: 10, per: Duration::from_secs(60) }),
+        rate_limit_ide: settings.get("RATE_LIMIT_IDE", Rate { requests: 20, per: Duration::from_secs(60) }),
+        rate_limit_solve: settings.get("RATE_LIMIT_SOLVE", Rate { requests: 10, per: Duration::from_secs(60) }),
+        metrics_token: settings.raw("METRICS_TOKEN"),
+    };
+
+    if CONFIG.set(config).is_err() {
+        panic!("Config already initialized");
+    }
+}
+
+pub fn get_config() -> &'static Config {
+    CONFIG.get().expect("Config not initialized. Call init_config() first.")
+}
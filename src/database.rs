@@ -1,8 +1,13 @@
 use std::env;
+use std::ops::{Deref, DerefMut};
 use std::sync::OnceLock;
+use std::time::Instant;
 use mysql::*;
 use mysql::prelude::*;
 
+use crate::logging;
+use crate::metrics;
+
 static POOL: OnceLock<Pool> = OnceLock::new();
 
 pub fn init_db() {
@@ -15,7 +20,9 @@ pub fn init_db() {
     let url = format!("mysql://{user}:{pass}@{host}:{port}/{db}");
     let pool = Pool::new(url.as_str()).expect("Couldn't connect to DB");
 
-    POOL.set(pool).ok().expect("DB Pool already initialized");
+    if POOL.set(pool).is_err() {
+        panic!("DB Pool already initialized");
+    }
 
     let mut conn = get_pool().get_conn().expect("No conn :(");
         
@@ -37,15 +44,67 @@ pub fn init_db() {
             output TEXT NOT NULL,
             solved BIGINT UNSIGNED DEFAULT 0,
             tried BIGINT UNSIGNED DEFAULT 0,
+            updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP,
             INDEX creator_idx (creator),
             FOREIGN KEY (creator) REFERENCES users(username) ON DELETE CASCADE
         )"
     ).unwrap();
 
-    println!("DB initialized");
+    // Tables created before `updated_at` existed get it added in place.
+    let has_updated_at: Option<u64> = conn.query_first(
+        r"SELECT COUNT(*) FROM information_schema.COLUMNS
+          WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = 'problems' AND COLUMN_NAME = 'updated_at'"
+    ).unwrap();
+    if has_updated_at == Some(0) {
+        conn.query_drop(
+            r"ALTER TABLE problems ADD COLUMN updated_at TIMESTAMP NOT NULL
+              DEFAULT CURRENT_TIMESTAMP ON UPDATE CURRENT_TIMESTAMP"
+        ).unwrap();
+    }
+
+    logging::info("DB initialized");
 }
 
 pub fn get_pool() -> &'static Pool {
     POOL.get().expect("DB not initialized. Call init_db() first.")
 }
 
+/// A connection checked out of the pool, counted as in use until it is dropped.
+pub struct Connection(PooledConn);
+
+impl Deref for Connection {
+    type Target = PooledConn;
+
+    fn deref(&self) -> &PooledConn {
+        &self.0
+    }
+}
+
+impl DerefMut for Connection {
+    fn deref_mut(&mut self) -> &mut PooledConn {
+        &mut self.0
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        metrics::DB_CONNECTIONS_IN_USE.dec(&[]);
+    }
+}
+
+/// Gets a connection from the pool, recording how long that took.
+pub fn get_conn() -> Result<Connection> {
+    let started = Instant::now();
+    let conn = get_pool().get_conn();
+    metrics::DB_CONNECTION_WAIT.observe(&[], started.elapsed().as_secs_f64());
+    match conn {
+        Ok(conn) => {
+            metrics::DB_CONNECTIONS_IN_USE.inc(&[]);
+            Ok(Connection(conn))
+        },
+        Err(e) => {
+            metrics::DB_CONNECTION_ERRORS.inc(&[]);
+            Err(e)
+        },
+    }
+}
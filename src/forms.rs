@@ -0,0 +1,259 @@
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Map, Value};
+
+use crate::network::{Request, Response};
+
+const USERNAME_LENGTH: (usize, usize) = (3, 32);
+/// bcrypt only looks at the first 72 bytes of a password.
+const PASSWORD_LENGTH: (usize, usize) = (8, 72);
+const TITLE_LENGTH: usize = 200;
+const DESCRIPTION_SIZE: usize = 16 * 1024;
+const CODE_SIZE: usize = 64 * 1024;
+const INPUT_SIZE: usize = 64 * 1024;
+const LANGUAGES: &[&str] = &["cpp", "python", "java"];
+
+/// Every problem found with a request body, so the client can fix them all at once.
+#[derive(Debug, Default)]
+pub struct FieldErrors {
+    errors: Vec<(String, String)>,
+}
+
+impl FieldErrors {
+    pub fn add(&mut self, field: &str, message: impl Into<String>) {
+        self.errors.push((field.to_string(), message.into()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Checks that `value` is present and at most `max` bytes long.
+    fn required(&mut self, field: &str, value: &str, max: usize) {
+        if value.trim().is_empty() {
+            self.add(field, "is required");
+        } else {
+            self.max_size(field, value, max);
+        }
+    }
+
+    fn max_size(&mut self, field: &str, value: &str, max: usize) {
+        if value.len() > max {
+            self.add(field, format!("must be at most {} bytes", max));
+        }
+    }
+
+    fn length(&mut self, field: &str, value: &str, (min, max): (usize, usize)) {
+        let len = value.chars().count();
+        if len < min || len > max {
+            self.add(field, format!("must be between {} and {} characters", min, max));
+        }
+    }
+
+    fn language(&mut self, value: &str) {
+        if value.is_empty() {
+            self.add("language", "is required");
+        } else if !LANGUAGES.iter().any(|l| l.eq_ignore_ascii_case(value)) {
+            self.add("language", format!("must be one of: {}", LANGUAGES.join(", ")));
+        }
+    }
+
+    pub fn into_response(self) -> Response {
+        let errors: Vec<Value> = self
+            .errors
+            .into_iter()
+            .map(|(field, message)| json!({ "field": field, "message": message }))
+            .collect();
+        Response::json(422, json!({ "message": "Validation failed", "errors": errors }))
+    }
+}
+
+/// Field-level rules for a request body, checked after it deserializes.
+pub trait Validate {
+    fn validate(&self, errors: &mut FieldErrors);
+}
+
+/// Reads the request body as `T` and validates it.
+///
+/// A body that isn't a JSON object is a 400. Anything else wrong with it is a
+/// single 422 listing each field error, including fields of the wrong type.
+pub fn extract<T: DeserializeOwned + Validate>(request: &Request) -> Result<T, Response> {
+    from_json(request.get_body())
+}
+
+/// `extract` for JSON that didn't come in a request body, e.g. a WebSocket message.
+pub fn from_json<T: DeserializeOwned + Validate>(json: &str) -> Result<T, Response> {
+    let fields: Map<String, Value> = serde_json::from_str(json)
+        .map_err(|_| Response::bad_request("Invalid JSON"))?;
+
+    // Every field has a default, so trying them one at a time tells us which
+    // are the wrong type. Those are reported as such and left out of the rest.
+    let mut errors = FieldErrors::default();
+    let mut valid = Map::new();
+    for (field, value) in fields {
+        let single = Value::Object(Map::from_iter([(field.clone(), value.clone())]));
+        match serde_json::from_value::<T>(single) {
+            Ok(_) => {
+                valid.insert(field, value);
+            },
+            Err(e) => errors.add(&field, e.to_string()),
+        }
+    }
+
+    let form = serde_json::from_value::<T>(Value::Object(valid))
+        .map_err(|e| Response::bad_request(e.to_string()))?;
+
+    let mut rules = FieldErrors::default();
+    form.validate(&mut rules);
+    for (field, message) in rules.errors {
+        if !errors.errors.iter().any(|(bad, _)| *bad == field) {
+            errors.add(&field, message);
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(form)
+    } else {
+        Err(errors.into_response())
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct SignupForm {
+    pub username: String,
+    pub password: String,
+}
+
+impl Validate for SignupForm {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if self.username.is_empty() {
+            errors.add("username", "is required");
+        } else {
+            errors.length("username", &self.username, USERNAME_LENGTH);
+            if !self.username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                errors.add("username", "may only contain letters, digits, '_' and '-'");
+            }
+        }
+
+        if self.password.is_empty() {
+            errors.add("password", "is required");
+        } else if self.password.len() > PASSWORD_LENGTH.1 {
+            errors.add("password", format!("must be at most {} bytes", PASSWORD_LENGTH.1));
+        } else {
+            errors.length("password", &self.password, PASSWORD_LENGTH);
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct LoginForm {
+    pub username: String,
+    pub password: String,
+}
+
+impl Validate for LoginForm {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if self.username.is_empty() {
+            errors.add("username", "is required");
+        }
+        if self.password.is_empty() {
+            errors.add("password", "is required");
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct RunForm {
+    pub code: String,
+    pub language: String,
+    pub input: String,
+}
+
+impl Validate for RunForm {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.required("code", &self.code, CODE_SIZE);
+        errors.language(&self.language);
+        errors.max_size("input", &self.input, INPUT_SIZE);
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ProblemForm {
+    pub title: String,
+    pub description: String,
+    pub input: String,
+    pub output: String,
+}
+
+impl Validate for ProblemForm {
+    fn validate(&self, errors: &mut FieldErrors) {
+        if self.title.trim().is_empty() {
+            errors.add("title", "is required");
+        } else if self.title.chars().count() > TITLE_LENGTH {
+            errors.add("title", format!("must be at most {} characters", TITLE_LENGTH));
+        }
+        errors.required("description", &self.description, DESCRIPTION_SIZE);
+        errors.max_size("input", &self.input, INPUT_SIZE);
+        errors.required("output", &self.output, INPUT_SIZE);
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct SolveForm {
+    pub code: String,
+    pub language: String,
+}
+
+impl Validate for SolveForm {
+    fn validate(&self, errors: &mut FieldErrors) {
+        errors.required("code", &self.code, CODE_SIZE);
+        errors.language(&self.language);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(body: &str) -> Request {
+        let raw = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        Request::from_raw(&raw)
+    }
+
+    fn errors(response: Response) -> Vec<String> {
+        let body: Value = serde_json::from_slice(response.get_body()).unwrap();
+        body["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["field"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn accepts_a_valid_body() {
+        let form: SignupForm = extract(&post(r#"{"username":"ada_l","password":"correct horse"}"#)).ok().unwrap();
+        assert_eq!(form.username, "ada_l");
+    }
+
+    #[test]
+    fn reports_every_field_error_at_once() {
+        let response = extract::<SignupForm>(&post(r#"{"username":"a b"}"#)).err().unwrap();
+        assert_eq!(response.get_status(), 422);
+        assert_eq!(errors(response), ["username", "password"]);
+
+        let response = extract::<RunForm>(&post(r#"{"code":5,"language":"cobol","input":[]}"#)).err().unwrap();
+        assert_eq!(errors(response), ["code", "input", "language"]);
+    }
+
+    #[test]
+    fn rejects_non_objects() {
+        let response = extract::<LoginForm>(&post("[1, 2]")).err().unwrap();
+        assert_eq!(response.get_status(), 400);
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+
+/// HTTP header fields in the order they were added.
+///
+/// Names are matched case-insensitively but written back with the spelling they
+/// were first given. A name may carry several values (e.g. `Set-Cookie`), each
+/// kept as its own entry.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> HeaderMap {
+        HeaderMap { entries: Vec::new() }
+    }
+
+    /// The first value for `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Every value for `name`, in order.
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.entries
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Sets `name` to a single value, replacing any existing ones in place.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        match self.entries.iter().position(|(key, _)| key.eq_ignore_ascii_case(name)) {
+            Some(pos) => {
+                self.entries[pos].1 = value.to_string();
+                let mut i = pos + 1;
+                while i < self.entries.len() {
+                    if self.entries[i].0.eq_ignore_ascii_case(name) {
+                        self.entries.remove(i);
+                    } else {
+                        i += 1;
+                    }
+                }
+            },
+            None => self.append(name, value),
+        }
+    }
+
+    /// Adds another value for `name`, keeping the existing ones.
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.entries.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// Whether the comma-separated list in `name` contains `token`, ignoring case,
+    /// as used by `Connection`, `Transfer-Encoding` and friends.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .iter()
+            .flat_map(|value| value.split(','))
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    }
+}
+
+impl From<HashMap<String, String>> for HeaderMap {
+    /// Sorted by name, so output doesn't depend on `HashMap` iteration order.
+    fn from(map: HashMap<String, String>) -> HeaderMap {
+        let mut entries: Vec<(String, String)> = map.into_iter().collect();
+        entries.sort();
+        HeaderMap { entries }
+    }
+}
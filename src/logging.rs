@@ -0,0 +1,308 @@
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde_json::{json, Map, Value};
+
+use crate::caching::civil_from_days;
+use crate::network::{Method, Request, Response};
+
+static LOGGER: OnceLock<(Level, Format)> = OnceLock::new();
+static NEXT_REQUEST: AtomicU64 = AtomicU64::new(0);
+
+/// Shown as `[REDACTED]` wherever they turn up: query strings, JSON bodies, headers.
+const SENSITIVE_FIELDS: &[&str] = &["password", "token", "jwt", "secret", "api_key", "access_token"];
+const SENSITIVE_HEADERS: &[&str] = &["Authorization", "Cookie", "Set-Cookie", "Proxy-Authorization"];
+const REDACTED: &str = "[REDACTED]";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Level::Off => "off",
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Level, ()> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(Level::Off),
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One JSON object per line.
+    Json,
+    /// Apache's Common Log Format, with latency and request id appended.
+    Common,
+}
+
+impl FromStr for Format {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Format, ()> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "common" | "clf" => Ok(Format::Common),
+            _ => Err(()),
+        }
+    }
+}
+
+pub fn init_logging(level: Level, format: Format) {
+    if LOGGER.set((level, format)).is_err() {
+        panic!("Logging already initialized");
+    }
+}
+
+fn settings() -> (Level, Format) {
+    LOGGER.get().copied().unwrap_or((Level::Info, Format::Common))
+}
+
+pub fn enabled(level: Level) -> bool {
+    level != Level::Off && level <= settings().0
+}
+
+fn now() -> (u64, u32) {
+    let elapsed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    (elapsed.as_secs(), elapsed.subsec_millis())
+}
+
+/// `2026-10-17T06:41:02.123Z`
+fn iso_time(secs: u64, millis: u32) -> String {
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, rem / 3600, rem % 3600 / 60, rem % 60, millis
+    )
+}
+
+/// `17/Oct/2026:06:41:02 +0000`
+fn clf_time(secs: u64) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+    format!(
+        "{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+        day, MONTHS[(month - 1) as usize], year, rem / 3600, rem % 3600 / 60, rem % 60
+    )
+}
+
+/// Writes a server message, such as a startup notice or a failed syscall.
+pub fn log(level: Level, message: &str) {
+    if !enabled(level) {
+        return;
+    }
+    let (secs, millis) = now();
+    let line = match settings().1 {
+        Format::Json => json!({ "time": iso_time(secs, millis), "level": level.as_str(), "message": message }).to_string(),
+        Format::Common => format!("[{}] {} {}", clf_time(secs), level.as_str(), message),
+    };
+    if level <= Level::Warn {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+pub fn error(message: &str) {
+    log(Level::Error, message);
+}
+
+pub fn info(message: &str) {
+    log(Level::Info, message);
+}
+
+/// A fresh id, unique within this process and unlikely to repeat across restarts.
+pub fn request_id() -> String {
+    static SEED: OnceLock<u64> = OnceLock::new();
+    let seed = SEED.get_or_init(|| {
+        let elapsed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        (elapsed.as_nanos() as u64 ^ (u64::from(std::process::id()) << 32)) & 0xffff_ffff_ffff
+    });
+    format!("{:012x}-{:08x}", seed, NEXT_REQUEST.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Whether an id sent by the client (or a proxy) is safe to adopt and echo.
+pub fn valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+fn sensitive(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    SENSITIVE_FIELDS.iter().any(|field| name.contains(field))
+}
+
+/// The request target with the values of sensitive query parameters hidden.
+pub fn redact_target(target: &str) -> String {
+    let Some((path, query)) = target.split_once('?') else { return target.to_string() };
+    let pairs: Vec<String> = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((key, _)) if sensitive(key) => format!("{}={}", key, REDACTED),
+            _ => pair.to_string(),
+        })
+        .collect();
+    format!("{}?{}", path, pairs.join("&"))
+}
+
+/// A JSON body with sensitive fields hidden at any depth. Anything that isn't
+/// JSON is summarized by size only, since we can't tell what's in it.
+pub fn redact_body(body: &str) -> Value {
+    fn redact(value: Value) -> Value {
+        match value {
+            Value::Object(map) => Value::Object(
+                map.into_iter()
+                    .map(|(key, value)| {
+                        let value = if sensitive(&key) { Value::from(REDACTED) } else { redact(value) };
+                        (key, value)
+                    })
+                    .collect::<Map<_, _>>(),
+            ),
+            Value::Array(items) => Value::Array(items.into_iter().map(redact).collect()),
+            other => other,
+        }
+    }
+
+    match serde_json::from_str::<Value>(body) {
+        Ok(value) => redact(value),
+        Err(_) => Value::from(format!("<{} bytes>", body.len())),
+    }
+}
+
+fn redacted_headers(request: &Request) -> Value {
+    let headers: Map<String, Value> = request
+        .get_headers()
+        .iter()
+        .map(|(name, value)| {
+            let hidden = SENSITIVE_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name));
+            (name.to_string(), Value::from(if hidden { REDACTED } else { value }))
+        })
+        .collect();
+    Value::Object(headers)
+}
+
+/// Writes the access log line for a finished request. Server errors log at
+/// `error`, client errors at `warn` and the rest at `info`; at `debug` the
+/// redacted headers and body are included too.
+pub fn access(request: &Request, response: &Response) {
+    let status = response.get_status();
+    let level = match status {
+        500.. => Level::Error,
+        400..=499 => Level::Warn,
+        _ => Level::Info,
+    };
+    if !enabled(level) {
+        return;
+    }
+
+    let (secs, millis) = now();
+    let latency = request.received_at().elapsed().as_secs_f64() * 1000.0;
+    let bytes = match (request.get_method(), status) {
+        (Method::HEAD, _) | (_, 204 | 304) => 0,
+        _ => response.get_body().len(),
+    };
+    let target = redact_target(request.get_target());
+    let client = request.get_client_ip().map(|ip| ip.to_string());
+    let request_id = request.get_request_id().unwrap_or("-");
+
+    let line = match settings().1 {
+        Format::Json => {
+            let mut entry = json!({
+                "time": iso_time(secs, millis),
+                "level": level.as_str(),
+                "request_id": request_id,
+                "client_ip": client,
+                "user": request.get_user(),
+                "method": request.get_method().as_str(),
+                "path": target,
+                "status": status,
+                "bytes": bytes,
+                "latency_ms": (latency * 1000.0).round() / 1000.0,
+            });
+            if enabled(Level::Debug) {
+                entry["headers"] = redacted_headers(request);
+                entry["body"] = redact_body(request.get_body());
+            }
+            entry.to_string()
+        },
+        Format::Common => {
+            let mut line = format!(
+                "{} - {} [{}] \"{} {} {}\" {} {} {:.3}ms {}",
+                client.as_deref().unwrap_or("-"),
+                request.get_user().map(String::as_str).unwrap_or("-"),
+                clf_time(secs),
+                request.get_method(),
+                target,
+                request.get_version(),
+                status,
+                bytes,
+                latency,
+                request_id
+            );
+            if enabled(Level::Debug) {
+                line.push_str(&format!(" headers={} body={}", redacted_headers(request), redact_body(request.get_body())));
+            }
+            line
+        },
+    };
+
+    if level <= Level::Warn {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_sensitive_values() {
+        assert_eq!(redact_target("/login?user=ada&password=hunter2"), "/login?user=ada&password=[REDACTED]");
+        assert_eq!(redact_target("/problem/1"), "/problem/1");
+
+        let body = redact_body(r#"{"username":"ada","password":"hunter2","nested":{"jwt_token":"x"}}"#);
+        assert_eq!(body, json!({ "username": "ada", "password": REDACTED, "nested": { "jwt_token": REDACTED } }));
+        assert_eq!(redact_body("password=hunter2"), json!("<16 bytes>"));
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(iso_time(784111777, 5), "1994-11-06T08:49:37.005Z");
+        assert_eq!(clf_time(784111777), "06/Nov/1994:08:49:37 +0000");
+    }
+
+    #[test]
+    fn generates_and_checks_request_ids() {
+        let (a, b) = (request_id(), request_id());
+        assert_ne!(a, b);
+        assert!(valid_request_id(&a));
+        assert!(!valid_request_id("has space"));
+        assert!(!valid_request_id(&"x".repeat(129)));
+    }
+}
@@ -0,0 +1,309 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+/// Upper bounds, in seconds, for request latencies.
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+/// Upper bounds, in seconds, for compiling and running submissions.
+const SANDBOX_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.0, 5.0, 10.0, 30.0];
+/// Upper bounds, in seconds, for waiting on a database connection.
+const POOL_WAIT_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+pub static HTTP_REQUESTS: Counter = Counter::new(
+    "http_requests_total",
+    "HTTP requests answered, by route pattern and status.",
+    &["method", "route", "status"],
+);
+pub static HTTP_REQUEST_DURATION: Histogram = Histogram::new(
+    "http_request_duration_seconds",
+    "Time from receiving a request to having its response ready.",
+    &["method", "route"],
+    LATENCY_BUCKETS,
+);
+pub static WORKER_QUEUE_DEPTH: Gauge = Gauge::new(
+    "worker_queue_depth",
+    "Jobs waiting for a free worker, requests and background judging alike.",
+    &[],
+);
+pub static JUDGES_RUNNING: Gauge = Gauge::new(
+    "judges_running",
+    "Submissions being run and judged in the sandbox.",
+    &[],
+);
+pub static JUDGE_VERDICTS: Counter = Counter::new(
+    "judge_verdicts_total",
+    "Judged submissions, by verdict.",
+    &["language", "verdict"],
+);
+pub static COMPILE_DURATION: Histogram = Histogram::new(
+    "sandbox_compile_duration_seconds",
+    "Time spent compiling code in the sandbox.",
+    &["language"],
+    SANDBOX_BUCKETS,
+);
+pub static RUN_DURATION: Histogram = Histogram::new(
+    "sandbox_run_duration_seconds",
+    "Time spent running code in the sandbox, time limit kills included.",
+    &["language"],
+    SANDBOX_BUCKETS,
+);
+pub static DB_CONNECTIONS_IN_USE: Gauge = Gauge::new(
+    "db_pool_connections_in_use",
+    "Database connections currently checked out of the pool.",
+    &[],
+);
+pub static DB_CONNECTION_WAIT: Histogram = Histogram::new(
+    "db_pool_wait_seconds",
+    "Time spent getting a connection from the database pool.",
+    &[],
+    POOL_WAIT_BUCKETS,
+);
+pub static DB_CONNECTION_ERRORS: Counter = Counter::new(
+    "db_pool_errors_total",
+    "Failures to get a connection from the database pool.",
+    &[],
+);
+
+/// Everything `render` reports, in the order it reports it.
+static REGISTRY: &[&dyn Metric] = &[
+    &HTTP_REQUESTS,
+    &HTTP_REQUEST_DURATION,
+    &WORKER_QUEUE_DEPTH,
+    &JUDGES_RUNNING,
+    &JUDGE_VERDICTS,
+    &COMPILE_DURATION,
+    &RUN_DURATION,
+    &DB_CONNECTIONS_IN_USE,
+    &DB_CONNECTION_WAIT,
+    &DB_CONNECTION_ERRORS,
+];
+
+/// Every metric in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut out = String::new();
+    for metric in REGISTRY {
+        metric.render(&mut out);
+    }
+    out
+}
+
+trait Metric: Sync {
+    fn render(&self, out: &mut String);
+}
+
+/// One value per combination of label values, in label order.
+type Series<T> = Mutex<BTreeMap<Vec<String>, T>>;
+
+fn update<T: Default>(series: &Series<T>, labels: &[&str], f: impl FnOnce(&mut T)) {
+    let mut series = series.lock().unwrap_or_else(|e| e.into_inner());
+    f(series.entry(labels.iter().map(|l| l.to_string()).collect()).or_default());
+}
+
+/// A metric without labels reads as zero before its first update, rather
+/// than missing.
+fn snapshot<T: Clone + Default>(series: &Series<T>, labels: &[&str]) -> Vec<(Vec<String>, T)> {
+    let series = series.lock().unwrap_or_else(|e| e.into_inner());
+    if series.is_empty() && labels.is_empty() {
+        return vec![(Vec::new(), T::default())];
+    }
+    series.iter().map(|(labels, value)| (labels.clone(), value.clone())).collect()
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// `{method="GET",route="/"}`, or nothing at all without labels.
+fn label_set(names: &[&str], values: &[String], extra: Option<(&str, &str)>) -> String {
+    let pairs: Vec<String> = names
+        .iter()
+        .copied()
+        .zip(values.iter().map(String::as_str))
+        .chain(extra)
+        .map(|(name, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    if pairs.is_empty() { String::new() } else { format!("{{{}}}", pairs.join(",")) }
+}
+
+/// A value that only goes up, such as a number of requests.
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Series<f64>,
+}
+
+impl Counter {
+    pub const fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Counter {
+        Counter { name, help, labels, values: Mutex::new(BTreeMap::new()) }
+    }
+
+    /// Adds one to the series for `labels`, given in the order they were declared.
+    pub fn inc(&self, labels: &[&str]) {
+        update(&self.values, labels, |value| *value += 1.0);
+    }
+}
+
+impl Metric for Counter {
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "counter");
+        for (labels, value) in snapshot(&self.values, self.labels) {
+            let _ = writeln!(out, "{}{} {}", self.name, label_set(self.labels, &labels, None), value);
+        }
+    }
+}
+
+/// A value that goes up and down, such as connections in use.
+pub struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Series<f64>,
+}
+
+impl Gauge {
+    pub const fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Gauge {
+        Gauge { name, help, labels, values: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn inc(&self, labels: &[&str]) {
+        update(&self.values, labels, |value| *value += 1.0);
+    }
+
+    pub fn dec(&self, labels: &[&str]) {
+        update(&self.values, labels, |value| *value -= 1.0);
+    }
+
+    /// Counts one more until the returned guard is dropped.
+    pub fn track(&'static self, labels: &[&str]) -> Tracked {
+        self.inc(labels);
+        Tracked { gauge: self, labels: labels.iter().map(|l| l.to_string()).collect() }
+    }
+}
+
+impl Metric for Gauge {
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "gauge");
+        for (labels, value) in snapshot(&self.values, self.labels) {
+            let _ = writeln!(out, "{}{} {}", self.name, label_set(self.labels, &labels, None), value);
+        }
+    }
+}
+
+/// Returned by `Gauge::track`.
+pub struct Tracked {
+    gauge: &'static Gauge,
+    labels: Vec<String>,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let labels: Vec<&str> = self.labels.iter().map(String::as_str).collect();
+        self.gauge.dec(&labels);
+    }
+}
+
+#[derive(Clone, Default)]
+struct Buckets {
+    /// Observations per bucket, not cumulative; the last one is `+Inf`.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+/// Observations sorted into buckets, such as request latencies.
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    bounds: &'static [f64],
+    values: Series<Buckets>,
+}
+
+impl Histogram {
+    pub const fn new(name: &'static str, help: &'static str, labels: &'static [&'static str], bounds: &'static [f64]) -> Histogram {
+        Histogram { name, help, labels, bounds, values: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn observe(&self, labels: &[&str], value: f64) {
+        let bucket = self.bounds.iter().position(|bound| value <= *bound).unwrap_or(self.bounds.len());
+        update(&self.values, labels, |buckets| {
+            buckets.counts.resize(self.bounds.len() + 1, 0);
+            buckets.counts[bucket] += 1;
+            buckets.sum += value;
+            buckets.count += 1;
+        });
+    }
+}
+
+impl Metric for Histogram {
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "histogram");
+        for (labels, buckets) in snapshot(&self.values, self.labels) {
+            let mut cumulative = 0;
+            for i in 0..=self.bounds.len() {
+                cumulative += buckets.counts.get(i).copied().unwrap_or(0);
+                let le = self.bounds.get(i).map_or("+Inf".to_string(), |bound| bound.to_string());
+                let _ = writeln!(out, "{}_bucket{} {}", self.name, label_set(self.labels, &labels, Some(("le", &le))), cumulative);
+            }
+            let labels = label_set(self.labels, &labels, None);
+            let _ = writeln!(out, "{}_sum{} {}", self.name, labels, buckets.sum);
+            let _ = writeln!(out, "{}_count{} {}", self.name, labels, buckets.count);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rendered(metric: &dyn Metric) -> String {
+        let mut out = String::new();
+        metric.render(&mut out);
+        out
+    }
+
+    #[test]
+    fn renders_counters_and_gauges() {
+        let counter = Counter::new("requests_total", "Requests.", &["route", "status"]);
+        counter.inc(&["/problem/:id", "200"]);
+        counter.inc(&["/problem/:id", "200"]);
+        counter.inc(&["say \"hi\"", "404"]);
+        assert_eq!(
+            rendered(&counter),
+            "# HELP requests_total Requests.\n# TYPE requests_total counter\n\
+             requests_total{route=\"/problem/:id\",status=\"200\"} 2\n\
+             requests_total{route=\"say \\\"hi\\\"\",status=\"404\"} 1\n"
+        );
+
+        static IN_USE: Gauge = Gauge::new("in_use", "In use.", &[]);
+        assert!(rendered(&IN_USE).ends_with("in_use 0\n"));
+        let first = IN_USE.track(&[]);
+        let second = IN_USE.track(&[]);
+        drop(first);
+        assert!(rendered(&IN_USE).ends_with("# TYPE in_use gauge\nin_use 1\n"));
+        drop(second);
+        assert!(rendered(&IN_USE).ends_with("in_use 0\n"));
+    }
+
+    #[test]
+    fn renders_cumulative_histogram_buckets() {
+        let histogram = Histogram::new("latency_seconds", "Latency.", &["route"], &[0.1, 1.0]);
+        for value in [0.05, 0.5, 0.5, 3.0] {
+            histogram.observe(&["/"], value);
+        }
+        assert_eq!(
+            rendered(&histogram),
+            "# HELP latency_seconds Latency.\n# TYPE latency_seconds histogram\n\
+             latency_seconds_bucket{route=\"/\",le=\"0.1\"} 1\n\
+             latency_seconds_bucket{route=\"/\",le=\"1\"} 3\n\
+             latency_seconds_bucket{route=\"/\",le=\"+Inf\"} 4\n\
+             latency_seconds_sum{route=\"/\"} 4.05\n\
+             latency_seconds_count{route=\"/\"} 4\n"
+        );
+    }
+}
@@ -0,0 +1,360 @@
+
+use crate::compression;
+use crate::config::get_config;
+use crate::logging;
+use crate::models::user::User;
+use crate::network::{Method, Request, Response};
+use crate::rate_limit::{self, Limiter, Rate};
+
+/// A hook around request handling. `before` hooks run in registration order and
+/// may answer the request themselves; `after` hooks then run in reverse order for
+/// every middleware whose `before` ran, whoever produced the response.
+pub trait Middleware: Send + Sync {
+    fn before(&self, _request: &mut Request) -> Option<Response> {
+        None
+    }
+
+    fn after(&self, _request: &Request, _response: &mut Response) {}
+}
+
+/// Runs `handler` inside `chain`.
+pub fn run<F>(chain: &[&dyn Middleware], request: &mut Request, handler: F) -> Response
+where
+    F: FnOnce(&mut Request) -> Response,
+{
+    let mut entered = 0;
+    let mut short_circuit = None;
+
+    for middleware in chain {
+        entered += 1;
+        if let Some(response) = middleware.before(request) {
+            short_circuit = Some(response);
+            break;
+        }
+    }
+
+    let mut response = match short_circuit {
+        Some(response) => response,
+        None => handler(request),
+    };
+
+    for middleware in chain[..entered].iter().rev() {
+        middleware.after(request, &mut response);
+    }
+    response
+}
+
+/// Tags each request with an id, echoed as `X-Request-Id`, and writes its
+/// access log line once the response is known. A well-formed id sent by the
+/// client or a proxy is kept, so one request can be followed across services.
+pub struct Logger;
+
+impl Middleware for Logger {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        let id = request
+            .get_header("X-Request-Id")
+            .filter(|id| logging::valid_request_id(id))
+            .map(str::to_string)
+            .unwrap_or_else(logging::request_id);
+        request.set_request_id(id);
+        None
+    }
+
+    fn after(&self, request: &Request, response: &mut Response) {
+        if let Some(id) = request.get_request_id() {
+            response.set_header("X-Request-Id", id);
+        }
+        logging::access(request, response);
+    }
+}
+
+/// Reports how long the request took in an `X-Response-Time` header.
+pub struct Timing;
+
+impl Middleware for Timing {
+    fn after(&self, request: &Request, response: &mut Response) {
+        let elapsed = request.received_at().elapsed().as_secs_f64() * 1000.0;
+        response.set_header("X-Response-Time", &format!("{:.3}ms", elapsed));
+    }
+}
+
+/// Compresses response bodies the client is willing to accept in encoded form.
+pub struct Compress {
+    pub min_size: usize,
+}
+
+impl Middleware for Compress {
+    fn after(&self, request: &Request, response: &mut Response) {
+        compression::compress(response, request.get_header("Accept-Encoding"), self.min_size);
+    }
+}
+
+/// A CORS policy. Only an `Origin` on the allow-list is echoed back, never the
+/// list itself. Entries are exact origins such as `https://example.com`,
+/// `https://*.example.com` for any subdomain, or `*` for any origin without
+/// credentials.
+pub struct Cors {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// Request headers a preflight may ask for; `*` allows any.
+    pub allowed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight answer, in seconds.
+    pub max_age: u64,
+}
+
+/// Splits a comma-separated setting into its trimmed, non-empty items.
+pub fn list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    if pattern == "*" || pattern.eq_ignore_ascii_case(origin) {
+        return true;
+    }
+    let (Some((scheme, host)), Some((origin_scheme, origin_host))) = (pattern.split_once("://*."), origin.split_once("://")) else {
+        return false;
+    };
+    // `*.example.com` covers `a.example.com` and `a.b.example.com`, but not `example.com`.
+    scheme.eq_ignore_ascii_case(origin_scheme)
+        && origin_host.len() > host.len() + 1
+        && origin_host[origin_host.len() - host.len()..].eq_ignore_ascii_case(host)
+        && origin_host.as_bytes()[origin_host.len() - host.len() - 1] == b'.'
+}
+
+impl Cors {
+    pub fn from_config() -> Cors {
+        let config = get_config();
+        Cors {
+            allowed_origins: list(&config.cors_allowed_origins),
+            allowed_methods: list(&config.cors_allowed_methods),
+            allowed_headers: list(&config.cors_allowed_headers),
+            allow_credentials: config.cors_allow_credentials,
+            max_age: config.cors_max_age,
+        }
+    }
+
+    /// For read-only data anyone may fetch: any origin, no credentials.
+    pub fn public() -> Cors {
+        Cors {
+            allowed_origins: vec!["*".to_string()],
+            allowed_methods: list("GET, HEAD, OPTIONS"),
+            allow_credentials: false,
+            ..Cors::from_config()
+        }
+    }
+
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins.iter().any(|pattern| origin_matches(pattern, origin))
+    }
+
+    fn allows_header(&self, name: &str) -> bool {
+        self.allowed_headers.iter().any(|h| h == "*" || h.eq_ignore_ascii_case(name))
+    }
+
+    /// Origins let in only by a `*` entry get a literal `*`, which browsers
+    /// never send credentials with; only listed origins may use them.
+    fn allow_origin(&self, response: &mut Response, origin: &str) {
+        let listed = self.allowed_origins.iter().any(|pattern| pattern != "*" && origin_matches(pattern, origin));
+        if !listed {
+            response.set_header("Access-Control-Allow-Origin", "*");
+            return;
+        }
+        response.set_header("Access-Control-Allow-Origin", origin);
+        if self.allow_credentials {
+            response.set_header("Access-Control-Allow-Credentials", "true");
+        }
+    }
+
+    /// Answers an `OPTIONS` request carrying `Access-Control-Request-Method`.
+    /// A refused preflight is still a 204, just without the headers that would
+    /// let the browser go ahead.
+    fn preflight(&self, origin: &str, method: &str, headers: Option<&str>) -> Response {
+        let mut response = Response::empty(204);
+        response.add_vary("Origin");
+        response.add_vary("Access-Control-Request-Method");
+        response.add_vary("Access-Control-Request-Headers");
+
+        let requested_headers = headers.map(list).unwrap_or_default();
+        if !self.allows_origin(origin)
+            || !self.allowed_methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+            || !requested_headers.iter().all(|h| self.allows_header(h))
+        {
+            return response;
+        }
+
+        self.allow_origin(&mut response, origin);
+        response.set_header("Access-Control-Allow-Methods", &self.allowed_methods.join(", "));
+        if !requested_headers.is_empty() {
+            response.set_header("Access-Control-Allow-Headers", &requested_headers.join(", "));
+        }
+        if self.max_age > 0 {
+            response.set_header("Access-Control-Max-Age", &self.max_age.to_string());
+        }
+        response
+    }
+}
+
+impl Middleware for Cors {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        if *request.get_method() != Method::OPTIONS {
+            return None;
+        }
+        let origin = request.get_header("Origin")?;
+        let method = request.get_header("Access-Control-Request-Method")?;
+        Some(self.preflight(origin, method, request.get_header("Access-Control-Request-Headers")))
+    }
+
+    fn after(&self, request: &Request, response: &mut Response) {
+        response.add_vary("Origin");
+        if response.get_header("Access-Control-Allow-Origin").is_some() {
+            return;
+        }
+        if let Some(origin) = request.get_header("Origin").filter(|origin| self.allows_origin(origin)) {
+            self.allow_origin(response, origin);
+        }
+    }
+}
+
+/// Throttles a route with a token bucket per client address and, once `Auth`
+/// has run, another per user, so neither switching accounts nor switching
+/// addresses gets around it. Over the limit is a 429 with `Retry-After`.
+pub struct RateLimit {
+    by_ip: Limiter,
+    by_user: Limiter,
+}
+
+impl RateLimit {
+    pub fn new(rate: Rate) -> RateLimit {
+        RateLimit {
+            by_ip: Limiter::new(rate),
+            by_user: Limiter::new(rate),
+        }
+    }
+}
+
+impl Middleware for RateLimit {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        // A Unix socket peer without a trusted proxy header has no address to go by.
+        let by_ip = request.get_client_ip().map_or(Ok(()), |ip| self.by_ip.check(&rate_limit::client_key(ip)));
+        let by_user = request.get_user().map_or(Ok(()), |user| self.by_user.check(user));
+
+        let retry_after = match (by_ip, by_user) {
+            (Ok(()), Ok(())) => return None,
+            (Err(a), Err(b)) => a.max(b),
+            (Err(secs), _) | (_, Err(secs)) => secs,
+        };
+        let response = Response::error(429, format!("Too many requests, try again in {} seconds", retry_after))
+            .with_header("Retry-After", &retry_after.to_string());
+        Some(response)
+    }
+}
+
+/// Requires a valid `Authorization: Bearer <jwt>` and records who sent it.
+pub struct Auth;
+
+impl Middleware for Auth {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        let token = match request.get_header("Authorization") {
+            Some(t) => t.split_whitespace().nth(1).unwrap_or("").to_string(),
+            None => {
+                return Some(Response::unauthorized("Missing or invalid 'Authorization' header"));
+            }
+        };
+
+        match User::get_username_from_jwt(&token) {
+            Ok(username) => {
+                request.set_user(username);
+                None
+            },
+            Err(e) => Some(Response::unauthorized(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors() -> Cors {
+        Cors {
+            allowed_origins: list("http://localhost:3000, https://*.example.com"),
+            allowed_methods: list("GET, POST"),
+            allowed_headers: list("Content-Type, Authorization"),
+            allow_credentials: true,
+            max_age: 600,
+        }
+    }
+
+    #[test]
+    fn matches_exact_and_wildcard_origins() {
+        assert!(origin_matches("http://localhost:3000", "http://localhost:3000"));
+        assert!(!origin_matches("http://localhost:3000", "http://localhost:3001"));
+        assert!(origin_matches("https://*.example.com", "https://app.example.com"));
+        assert!(origin_matches("https://*.example.com", "https://a.b.example.com"));
+        assert!(!origin_matches("https://*.example.com", "https://example.com"));
+        assert!(!origin_matches("https://*.example.com", "https://evilexample.com"));
+        assert!(!origin_matches("https://*.example.com", "http://app.example.com"));
+        assert!(origin_matches("*", "https://anywhere.dev"));
+    }
+
+    #[test]
+    fn echoes_only_the_matching_origin() {
+        let cors = cors();
+        let mut response = Response::empty(200);
+        cors.after(&Request::from_raw("GET / HTTP/1.1\r\nOrigin: https://app.example.com\r\n\r\n"), &mut response);
+        assert_eq!(response.get_header("Access-Control-Allow-Origin"), Some("https://app.example.com"));
+        assert_eq!(response.get_header("Vary"), Some("Origin"));
+
+        let mut response = Response::empty(200);
+        cors.after(&Request::from_raw("GET / HTTP/1.1\r\nOrigin: https://evil.dev\r\n\r\n"), &mut response);
+        assert!(response.get_header("Access-Control-Allow-Origin").is_none());
+    }
+
+    #[test]
+    fn never_lends_credentials_through_a_wildcard() {
+        let cors = Cors { allowed_origins: list("*, http://localhost:3000"), ..cors() };
+        let mut response = Response::empty(200);
+        cors.after(&Request::from_raw("GET / HTTP/1.1\r\nOrigin: https://evil.dev\r\n\r\n"), &mut response);
+        assert_eq!(response.get_header("Access-Control-Allow-Origin"), Some("*"));
+        assert!(response.get_header("Access-Control-Allow-Credentials").is_none());
+
+        let mut preflight = Request::from_raw(
+            "OPTIONS /solve/1 HTTP/1.1\r\nOrigin: https://evil.dev\r\nAccess-Control-Request-Method: POST\r\n\r\n",
+        );
+        let response = cors.before(&mut preflight).unwrap();
+        assert_eq!(response.get_header("Access-Control-Allow-Origin"), Some("*"));
+        assert!(response.get_header("Access-Control-Allow-Credentials").is_none());
+
+        let mut response = Response::empty(200);
+        cors.after(&Request::from_raw("GET / HTTP/1.1\r\nOrigin: http://localhost:3000\r\n\r\n"), &mut response);
+        assert_eq!(response.get_header("Access-Control-Allow-Origin"), Some("http://localhost:3000"));
+        assert_eq!(response.get_header("Access-Control-Allow-Credentials"), Some("true"));
+    }
+
+    #[test]
+    fn answers_preflights() {
+        let cors = cors();
+        let mut preflight = Request::from_raw(
+            "OPTIONS /solve/1 HTTP/1.1\r\nOrigin: http://localhost:3000\r\n\
+             Access-Control-Request-Method: POST\r\nAccess-Control-Request-Headers: authorization, content-type\r\n\r\n",
+        );
+        let response = cors.before(&mut preflight).unwrap();
+        assert_eq!(response.get_status(), 204);
+        assert_eq!(response.get_header("Access-Control-Allow-Origin"), Some("http://localhost:3000"));
+        assert_eq!(response.get_header("Access-Control-Allow-Headers"), Some("authorization, content-type"));
+        assert_eq!(response.get_header("Access-Control-Max-Age"), Some("600"));
+
+        let mut preflight = Request::from_raw(
+            "OPTIONS /solve/1 HTTP/1.1\r\nOrigin: http://localhost:3000\r\n\
+             Access-Control-Request-Method: POST\r\nAccess-Control-Request-Headers: x-secret\r\n\r\n",
+        );
+        let response = cors.before(&mut preflight).unwrap();
+        assert!(response.get_header("Access-Control-Allow-Origin").is_none());
+    }
+}
@@ -1,10 +1,61 @@
-use std::fs::{self, File};
-use std::io::Write;
-use std::process::{Command, Stdio};
-use std::time::{Duration, Instant};
-use std::path::{PathBuf, Path};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::metrics;
 
 const TIME_LIMIT: u64 = 2;
+/// Wall-clock limit for an interactive run, which mostly waits on the user.
+/// CPU time is still held to `TIME_LIMIT`.
+pub const SESSION_TIME_LIMIT: u64 = 120;
+const CONTAINER: &str = "code-sandbox";
+const SANDBOX_ROOT: &str = "/sandbox";
+
+static NEXT_WORKSPACE: AtomicU64 = AtomicU64::new(0);
+
+/// Workspaces of executions still in progress, keyed by id, so shutdown can
+/// clean up after runs it didn't wait for.
+static ACTIVE_WORKSPACES: Mutex<Option<HashMap<String, (PathBuf, String)>>> = Mutex::new(None);
+
+/// Kills whatever is still running in a workspace inside the container: the
+/// process tree under the shell that started the program, found by the
+/// workspace on its command line, and anything that escaped that tree but
+/// still works in the workspace. The bracket keeps the pattern from matching
+/// the killing shell itself.
+fn kill_workspace_processes(id: &str) {
+    let pattern = format!("[{}]{}", &id[..1], &id[1..]);
+    let script = format!(
+        "kill_tree() {{ kill -STOP \"$1\" 2>/dev/null; for child in $(pgrep -P \"$1\"); do kill_tree \"$child\"; done; kill -KILL \"$1\" 2>/dev/null; }}; \
+         for pid in $(pgrep -f '{pattern}'); do kill_tree \"$pid\"; done; \
+         for proc in /proc/[0-9]*; do case \"$(readlink \"$proc/cwd\")\" in {root}/{pattern}*) kill -KILL \"${{proc#/proc/}}\" 2>/dev/null;; esac; done",
+        pattern = pattern,
+        root = SANDBOX_ROOT,
+    );
+    let _ = Command::new("docker").args(["exec", CONTAINER, "/bin/sh", "-c", &script]).output();
+}
+
+fn remove_workspace(host_dir: &Path, sandbox_dir: &str) {
+    let _ = std::fs::remove_dir_all(host_dir);
+    let _ = std::process::Command::new("docker")
+        .args(["exec", CONTAINER, "rm", "-rf", sandbox_dir])
+        .output();
+}
+
+/// Removes every workspace that is still registered, host and sandbox side,
+/// along with anything still running in it.
+pub fn cleanup_workspaces() {
+    let active = match ACTIVE_WORKSPACES.lock() {
+        Ok(mut guard) => guard.take().unwrap_or_default(),
+        Err(_) => return,
+    };
+    for (id, (host_dir, sandbox_dir)) in &active {
+        kill_workspace_processes(id);
+        remove_workspace(host_dir, sandbox_dir);
+    }
+}
 
 pub enum Language {
     Cpp,
@@ -12,6 +63,100 @@ pub enum Language {
     Java,
 }
 
+impl Language {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Language::Cpp => "cpp",
+            Language::Python => "python",
+            Language::Java => "java",
+        }
+    }
+}
+
+/// A directory owned by a single execution, mirrored on the host and inside the
+/// sandbox container. Both sides are removed when the workspace is dropped.
+struct Workspace {
+    id: String,
+    host_dir: PathBuf,
+    sandbox_dir: String,
+}
+
+impl Workspace {
+    fn new() -> Result<Workspace, String> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        let id = format!(
+            "run-{}-{}-{}",
+            std::process::id(),
+            NEXT_WORKSPACE.fetch_add(1, Ordering::Relaxed),
+            nanos
+        );
+
+        let host_dir = std::env::temp_dir().join(&id);
+        std::fs::create_dir_all(&host_dir)
+            .map_err(|e| format!("Failed to create workspace: {}", e))?;
+
+        let workspace = Workspace {
+            sandbox_dir: format!("{}/{}", SANDBOX_ROOT, id),
+            id,
+            host_dir,
+        };
+        if let Ok(mut active) = ACTIVE_WORKSPACES.lock() {
+            active.get_or_insert_with(HashMap::new).insert(
+                workspace.id.clone(),
+                (workspace.host_dir.clone(), workspace.sandbox_dir.clone()),
+            );
+        }
+        Ok(workspace)
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        if let Ok(mut active) = ACTIVE_WORKSPACES.lock()
+            && let Some(active) = active.as_mut()
+        {
+            active.remove(&self.id);
+        }
+        remove_workspace(&self.host_dir, &self.sandbox_dir);
+    }
+}
+
+/// The outcome of judging a submission, see `CodeHandler::judge`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accepted,
+    WrongAnswer,
+    CompileError,
+    RuntimeError,
+    TimeLimitExceeded,
+    /// The sandbox itself failed; the code may never have run.
+    InternalError,
+}
+
+impl Verdict {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Verdict::Accepted => "accepted",
+            Verdict::WrongAnswer => "wrong_answer",
+            Verdict::CompileError => "compile_error",
+            Verdict::RuntimeError => "runtime_error",
+            Verdict::TimeLimitExceeded => "time_limit_exceeded",
+            Verdict::InternalError => "internal_error",
+        }
+    }
+}
+
+/// How far an execution has got, reported through `CodeHandler::on_progress`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Compiling,
+    /// Running the program against test case `test`, counting from 1.
+    Running { test: usize },
+}
+
 pub struct CodeHandler {
     code: String,
     lang: Language,
@@ -20,6 +165,9 @@ pub struct CodeHandler {
     error: String,
     runtime: String,
     memory: String,
+    progress: Option<Box<dyn Fn(Stage) + Send>>,
+    /// Why the code failed, once it has been compiled or run and did.
+    failure: Option<Verdict>,
 }
 
 impl CodeHandler {
@@ -38,6 +186,8 @@ impl CodeHandler {
             error: String::new(),
             runtime: String::new(),
             memory: String::new(),
+            progress: None,
+            failure: None,
         }
     }
 
@@ -53,6 +203,17 @@ impl CodeHandler {
         self.lang = language;
     }
 
+    /// Calls `callback` as the execution moves from one stage to the next.
+    pub fn on_progress(&mut self, callback: impl Fn(Stage) + Send + 'static) {
+        self.progress = Some(Box::new(callback));
+    }
+
+    fn report(&self, stage: Stage) {
+        if let Some(progress) = &self.progress {
+            progress(stage);
+        }
+    }
+
     pub fn get_output(&self) -> String {
         self.output.clone()
     }
@@ -71,111 +232,155 @@ impl CodeHandler {
 
     pub fn execute(&mut self) -> Result<(), String> {
         let start_time = std::time::Instant::now();
-        let result = match self.lang {
-            Language::Cpp => self.execute_cpp(),
-            Language::Python => self.execute_python(),
-            Language::Java => self.execute_java(),
-        };
+        let result = Workspace::new()
+            .and_then(|workspace| {
+                self.prepare_files(&workspace)?;
+                self.copy_to_container(&workspace)?;
+                self.run(&workspace)
+            })
+            .inspect_err(|e| {
+                if self.error.is_empty() {
+                    self.error = e.clone();
+                }
+            });
         self.runtime = format!("{:.3}s", start_time.elapsed().as_secs_f64());
         result
     }
 
-    fn prepare_files(&self) -> Result<(PathBuf, PathBuf), String> {
-        let temp_dir = std::env::temp_dir();
-        let source_path = temp_dir.join(match self.lang {
+    /// Runs the code and compares its output with `expected`. Matching output
+    /// is accepted even if the program also complained on stderr.
+    pub fn judge(&mut self, expected: &str) -> Verdict {
+        let _running = metrics::JUDGES_RUNNING.track(&[]);
+        let verdict = match self.execute() {
+            _ if self.output.trim() == expected.trim() => Verdict::Accepted,
+            Ok(()) => Verdict::WrongAnswer,
+            Err(_) => self.failure.unwrap_or(Verdict::InternalError),
+        };
+        metrics::JUDGE_VERDICTS.inc(&[self.lang.as_str(), verdict.as_str()]);
+        verdict
+    }
+
+    fn prepare_files(&self, workspace: &Workspace) -> Result<(), String> {
+        let source_path = workspace.host_dir.join(match self.lang {
             Language::Cpp => "program.cpp",
             Language::Python => "program.py",
             Language::Java => "Main.java",
         });
-        let input_path = temp_dir.join("input.txt");
+        let input_path = workspace.host_dir.join("input.txt");
 
         std::fs::write(&source_path, &self.code)
             .map_err(|e| format!("Failed to write source code: {}", e))?;
         std::fs::write(&input_path, &self.input)
             .map_err(|e| format!("Failed to write input: {}", e))?;
 
-        Ok((source_path, input_path))
+        Ok(())
     }
 
-    fn execute_cpp(&mut self) -> Result<(), String> {
-        let (source_path, input_path) = self.prepare_files()?;
-        
-        if let Err(e) = self.copy_to_container(&source_path, "/sandbox/program.cpp") {
-            self.error = e;
-            return Err(self.error.clone());
-        }
-        
-        if let Err(e) = self.copy_to_container(&input_path, "/sandbox/input.txt") {
-            self.error = e;
-            return Err(self.error.clone());
+    /// The compiler invocation, for languages that have one.
+    fn compile_command(&self) -> Option<&'static str> {
+        match self.lang {
+            Language::Cpp => Some("g++ program.cpp -o program -std=c++17"),
+            Language::Python => None,
+            Language::Java => Some("javac Main.java"),
         }
+    }
 
-        if let Err(e) = self.run_in_container("g++ /sandbox/program.cpp -o /sandbox/program -std=c++17") {
-            return Err(e);
+    fn run_command(&self) -> &'static str {
+        match self.lang {
+            Language::Cpp => "./program",
+            Language::Python => "python3 program.py",
+            Language::Java => "java Main",
         }
-
-        self.run_and_capture(
-            format!("cd /sandbox && timeout -s KILL {} ./program < input.txt", TIME_LIMIT),
-            &source_path,
-            &input_path,
-        )
     }
 
-    fn execute_python(&mut self) -> Result<(), String> {
-        let (source_path, input_path) = self.prepare_files()?;
-        
-        if let Err(e) = self.copy_to_container(&source_path, "/sandbox/program.py") {
-            self.error = e;
-            return Err(self.error.clone());
-        }
-        
-        if let Err(e) = self.copy_to_container(&input_path, "/sandbox/input.txt") {
-            self.error = e;
-            return Err(self.error.clone());
+    fn compile(&mut self, workspace: &Workspace) -> Result<(), String> {
+        let Some(command) = self.compile_command() else { return Ok(()) };
+        self.report(Stage::Compiling);
+        let started = Instant::now();
+        let result = self.run_in_container(&format!("cd {} && {}", workspace.sandbox_dir, command));
+        metrics::COMPILE_DURATION.observe(&[self.lang.as_str()], started.elapsed().as_secs_f64());
+        if result.is_err() {
+            self.failure = Some(Verdict::CompileError);
         }
+        result
+    }
 
-        self.run_and_capture(
-            format!("cd /sandbox && timeout -s KILL {} python3 program.py < input.txt", TIME_LIMIT),
-            &source_path,
-            &input_path,
-        )
+    fn run(&mut self, workspace: &Workspace) -> Result<(), String> {
+        self.compile(workspace)?;
+        self.report(Stage::Running { test: 1 });
+        let started = Instant::now();
+        let result = self.run_and_capture(format!(
+            "cd {} && timeout -s KILL {} {} < input.txt",
+            workspace.sandbox_dir,
+            TIME_LIMIT,
+            self.run_command()
+        ));
+        metrics::RUN_DURATION.observe(&[self.lang.as_str()], started.elapsed().as_secs_f64());
+        result
     }
 
-    fn execute_java(&mut self) -> Result<(), String> {
-        let (source_path, input_path) = self.prepare_files()?;
-        
-        if let Err(e) = self.copy_to_container(&source_path, "/sandbox/Main.java") {
-            self.error = e;
-            return Err(self.error.clone());
-        }
-        
-        if let Err(e) = self.copy_to_container(&input_path, "/sandbox/input.txt") {
-            self.error = e;
-            return Err(self.error.clone());
-        }
+    /// Compiles the code and starts it with its standard streams piped to us,
+    /// for a user to talk to. Compile errors come back as `Err`.
+    pub fn start_interactive(&mut self) -> Result<InteractiveRun, String> {
+        let workspace = Workspace::new()?;
+        self.prepare_files(&workspace)?;
+        self.copy_to_container(&workspace)?;
+        self.compile(&workspace)?;
 
-        if let Err(e) = self.run_in_container("cd /sandbox && javac Main.java") {
-            return Err(e);
-        }
+        let child = Command::new("docker")
+            .args([
+                "exec",
+                "-i",
+                CONTAINER,
+                "/bin/sh",
+                "-c",
+                // The trailing `exit` keeps the shell from exec'ing `timeout`:
+                // it stays, its command line naming the workspace, so
+                // `InteractiveRun::kill` can find the program.
+                &format!(
+                    "cd {} && ulimit -t {} && timeout -s KILL {} {}; exit $?",
+                    workspace.sandbox_dir,
+                    TIME_LIMIT,
+                    SESSION_TIME_LIMIT,
+                    self.run_command()
+                ),
+            ])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to start program: {}", e))?;
 
-        self.run_and_capture(
-            format!("cd /sandbox && timeout -s KILL {} java Main < input.txt", TIME_LIMIT),
-            &source_path,
-            &input_path,
-        )
+        Ok(InteractiveRun {
+            child: Mutex::new(child),
+            started: Instant::now(),
+            workspace,
+        })
     }
 
-    fn copy_to_container(&self, host_path: &Path, container_path: &str) -> Result<(), String> {
-        std::process::Command::new("docker")
-            .args(&["cp", host_path.to_str().unwrap(), &format!("code-sandbox:{}", container_path)])
+    fn copy_to_container(&self, workspace: &Workspace) -> Result<(), String> {
+        // Copying the directory itself creates the per-run sandbox directory.
+        let output = std::process::Command::new("docker")
+            .args([
+                "cp",
+                workspace.host_dir.to_str().ok_or("Invalid workspace path")?,
+                &format!("{}:{}", CONTAINER, workspace.sandbox_dir),
+            ])
             .output()
             .map_err(|e| format!("Failed to copy file to container: {}", e))?;
+
+        if !output.status.success() {
+            return Err(format!(
+                "Failed to copy file to container: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
         Ok(())
     }
 
     fn run_in_container(&mut self, cmd: &str) -> Result<(), String> {
         let status = std::process::Command::new("docker")
-            .args(&["exec", "code-sandbox", "/bin/sh", "-c", cmd])
+            .args(["exec", CONTAINER, "/bin/sh", "-c", cmd])
             .output()
             .map_err(|e| format!("Execution failed: {}", e))?;
 
@@ -190,9 +395,9 @@ impl CodeHandler {
         Ok(())
     }
 
-    fn run_and_capture(&mut self, cmd: String, src: &Path, inp: &Path) -> Result<(), String> {
+    fn run_and_capture(&mut self, cmd: String) -> Result<(), String> {
         let output = std::process::Command::new("docker")
-            .args(&["exec", "code-sandbox", "/bin/sh", "-c", &cmd])
+            .args(["exec", CONTAINER, "/bin/sh", "-c", &cmd])
             .output()
             .map_err(|e| {
                 self.error = format!("Failed to run command: {}", e);
@@ -203,24 +408,20 @@ impl CodeHandler {
         let stderr = String::from_utf8_lossy(&output.stderr).to_string();
         let exit_code = output.status.code().unwrap_or(0);
 
-        // Cleanup first
-        let _ = std::fs::remove_file(src);
-        let _ = std::fs::remove_file(inp);
-        let _ = std::process::Command::new("docker")
-            .args(&["exec", "code-sandbox", "/bin/sh", "-c", "rm -f /sandbox/*"])
-            .output();
-
         if exit_code == 124 || exit_code == 137 {
+            self.failure = Some(Verdict::TimeLimitExceeded);
             self.error = "Time Limit Exceeded".to_string();
             return Err(self.error.clone());
         }
 
         if !stderr.is_empty() {
+            self.failure = Some(Verdict::RuntimeError);
             self.error = stderr;
             return Err(self.error.clone());
         }
 
         if !output.status.success() {
+            self.failure = Some(Verdict::RuntimeError);
             self.error = "Runtime Error".to_string();
             return Err(self.error.clone());
         }
@@ -228,3 +429,47 @@ impl CodeHandler {
         Ok(())
     }
 }
+
+/// A program started by `CodeHandler::start_interactive`. Its workspace is
+/// removed once this is dropped.
+pub struct InteractiveRun {
+    child: Mutex<Child>,
+    started: Instant,
+    workspace: Workspace,
+}
+
+impl InteractiveRun {
+    fn child(&self) -> std::sync::MutexGuard<'_, Child> {
+        self.child.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The program's standard streams. Each can only be taken once.
+    pub fn take_stdin(&self) -> Option<ChildStdin> {
+        self.child().stdin.take()
+    }
+
+    pub fn take_stdout(&self) -> Option<ChildStdout> {
+        self.child().stdout.take()
+    }
+
+    pub fn take_stderr(&self) -> Option<ChildStderr> {
+        self.child().stderr.take()
+    }
+
+    /// Stops the program. Killing our `docker exec` alone would leave it
+    /// running in the container until the session time limit.
+    pub fn kill(&self) {
+        kill_workspace_processes(&self.workspace.id);
+        let _ = self.child().kill();
+    }
+
+    /// Waits for the program to end. Call it once its output has closed, so
+    /// `kill` isn't kept waiting.
+    pub fn wait(&self) -> std::io::Result<ExitStatus> {
+        self.child().wait()
+    }
+
+    pub fn runtime(&self) -> String {
+        format!("{:.3}s", self.started.elapsed().as_secs_f64())
+    }
+}
@@ -1,3 +1,4 @@
 pub mod codehandler;
 pub mod problem;
+pub mod submission;
 pub mod user;
@@ -1,4 +1,4 @@
-use crate::database::get_pool;
+use crate::database::get_conn;
 use mysql::prelude::*;
 use mysql::{Row, FromRowError};
 use serde::{Serialize, Deserialize};
@@ -13,6 +13,8 @@ pub struct Problem {
     pub output: String,
     pub solved: u64,
     pub tried: u64,
+    /// Unix seconds of the last change, counts included.
+    pub updated_at: Option<u64>,
 }
 
 impl FromRow for Problem {
@@ -31,6 +33,7 @@ impl FromRow for Problem {
             output: row.get("output").ok_or(FromRowError(row.clone()))?,
             solved: row.get("solved").ok_or(FromRowError(row.clone()))?,
             tried: row.get("tried").ok_or(FromRowError(row.clone()))?,
+            updated_at: row.get("updated_at"),
         })
     }
 }
@@ -46,12 +49,12 @@ impl Problem {
             output,
             solved: 0,
             tried: 0,
+            updated_at: None,
         }
     }
 
     pub fn save(&mut self) -> Result<(), String> {
-        let mut conn = get_pool()
-            .get_conn()
+        let mut conn = get_conn()
             .map_err(|e| format!("Database connection failed: {}", e))?;
 
         match self.id {
@@ -94,32 +97,44 @@ impl Problem {
     }
 
     pub fn find_by_id(id: u64) -> Result<Option<Problem>, String> {
-        let mut conn = get_pool()
-            .get_conn()
+        let mut conn = get_conn()
             .map_err(|e| format!("Database connection failed: {}", e))?;
 
         conn.exec_first(
-            "SELECT id, creator, title, description, input, output, solved, tried 
+            "SELECT id, creator, title, description, input, output, solved, tried,
+                    UNIX_TIMESTAMP(updated_at) AS updated_at
              FROM problems WHERE id = ?",
             (id,)
         ).map_err(|e| format!("Database query failed: {}", e))
     }
 
     pub fn get_all() -> Result<Vec<Problem>, String> {
-        let mut conn = get_pool()
-            .get_conn()
+        let mut conn = get_conn()
             .map_err(|e| format!("Database connection failed: {}", e))?;
 
         conn.exec(
-            "SELECT id, creator, title, description, input, output, solved, tried 
+            "SELECT id, creator, title, description, input, output, solved, tried,
+                    UNIX_TIMESTAMP(updated_at) AS updated_at
              FROM problems ORDER BY id DESC",
             (),
         ).map_err(|e| format!("Database query failed: {}", e))
     }
 
+    /// One page of problems, newest first. Pages are numbered from 1.
+    pub fn get_page(page: u64, per_page: u64) -> Result<Vec<Problem>, String> {
+        let mut conn = get_conn()
+            .map_err(|e| format!("Database connection failed: {}", e))?;
+
+        conn.exec(
+            "SELECT id, creator, title, description, input, output, solved, tried,
+                    UNIX_TIMESTAMP(updated_at) AS updated_at
+             FROM problems ORDER BY id DESC LIMIT ? OFFSET ?",
+            (per_page, (page - 1) * per_page),
+        ).map_err(|e| format!("Database query failed: {}", e))
+    }
+
     pub fn increment_tried(id: u64) -> Result<(), String> {
-        let mut conn = get_pool()
-            .get_conn()
+        let mut conn = get_conn()
             .map_err(|e| format!("Database connection failed: {}", e))?;
 
         conn.exec_drop(
@@ -129,8 +144,7 @@ impl Problem {
     }
 
     pub fn increment_solved(id: u64) -> Result<(), String> {
-        let mut conn = get_pool()
-            .get_conn()
+        let mut conn = get_conn()
             .map_err(|e| format!("Database connection failed: {}", e))?;
 
         conn.exec_drop(
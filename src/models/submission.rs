@@ -0,0 +1,128 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde_json::Value;
+
+use crate::network::Event;
+
+/// How long a judged submission's events stay around for late subscribers.
+const RETENTION: Duration = Duration::from_secs(10 * 60);
+
+/// The progress of one submission as it is judged. Nothing here is persisted:
+/// it only lives long enough for the UI to follow along.
+struct Submission {
+    events: Vec<Event>,
+    subscribers: Vec<Sender<Event>>,
+    finished: Option<Instant>,
+}
+
+static SUBMISSIONS: Mutex<Option<HashMap<String, Submission>>> = Mutex::new(None);
+
+/// Ids double as the capability to watch a submission, since `EventSource`
+/// can't send an `Authorization` header, so they must not be guessable.
+/// `RandomState` is seeded from the OS and gives fresh keys on every call.
+fn new_id() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    (0..2)
+        .map(|_| {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u128(nanos);
+            format!("{:016x}", hasher.finish())
+        })
+        .collect()
+}
+
+fn with_submissions<T>(f: impl FnOnce(&mut HashMap<String, Submission>) -> T) -> T {
+    let mut guard = SUBMISSIONS.lock().unwrap_or_else(|e| e.into_inner());
+    f(guard.get_or_insert_with(HashMap::new))
+}
+
+/// Registers a new submission, already `queued`, and returns its id.
+pub fn create(data: Value) -> String {
+    let id = new_id();
+    with_submissions(|submissions| {
+        submissions.retain(|_, s| s.finished.is_none_or(|at| at.elapsed() < RETENTION));
+        submissions.insert(id.clone(), Submission {
+            events: vec![Event::new("queued", data.to_string()).with_id("1")],
+            subscribers: Vec::new(),
+            finished: None,
+        });
+    });
+    id
+}
+
+/// Records an event for submission `id` and sends it to everyone watching.
+/// The last one, `finish`ed, ends their streams.
+fn record(id: &str, name: &str, data: Value, finish: bool) {
+    with_submissions(|submissions| {
+        let Some(submission) = submissions.get_mut(id) else { return };
+        if submission.finished.is_some() {
+            return;
+        }
+        let event = Event::new(name, data.to_string()).with_id((submission.events.len() + 1).to_string());
+        // A failed send means that client has gone away.
+        submission.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+        submission.events.push(event);
+        if finish {
+            submission.subscribers.clear();
+            submission.finished = Some(Instant::now());
+        }
+    });
+}
+
+pub fn publish(id: &str, name: &str, data: Value) {
+    record(id, name, data, false);
+}
+
+pub fn finish(id: &str, name: &str, data: Value) {
+    record(id, name, data, true);
+}
+
+/// Follows submission `id`: every event so far after `last_event_id`, then
+/// the rest as they happen. `None` if there's no such submission (any more).
+pub fn subscribe(id: &str, last_event_id: Option<usize>) -> Option<Receiver<Event>> {
+    with_submissions(|submissions| {
+        let submission = submissions.get_mut(id)?;
+        let (sender, receiver) = mpsc::channel();
+        for event in submission.events.iter().skip(last_event_id.unwrap_or(0)) {
+            let _ = sender.send(event.clone());
+        }
+        if submission.finished.is_none() {
+            submission.subscribers.push(sender);
+        }
+        Some(receiver)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn names(events: &Receiver<Event>) -> Vec<String> {
+        events.try_iter().map(|e| e.name.unwrap_or_default()).collect()
+    }
+
+    #[test]
+    fn replays_history_then_follows_until_finished() {
+        let id = create(json!({ "problem_id": 1 }));
+        assert_ne!(id, create(json!({})));
+
+        publish(&id, "compiling", json!({}));
+        let early = subscribe(&id, None).unwrap();
+        publish(&id, "running", json!({ "test": 1 }));
+        finish(&id, "verdict", json!({ "accepted": true }));
+        assert_eq!(names(&early), ["queued", "compiling", "running", "verdict"]);
+        assert!(early.recv().is_err(), "the stream ends with the verdict");
+
+        // Reconnecting with Last-Event-ID picks up where the client left off.
+        let late = subscribe(&id, Some(2)).unwrap();
+        assert_eq!(names(&late), ["running", "verdict"]);
+        assert!(late.recv().is_err());
+
+        assert!(subscribe("missing", None).is_none());
+    }
+}
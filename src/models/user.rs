@@ -1,4 +1,4 @@
-use crate::database::get_pool;
+use crate::database::get_conn;
 use bcrypt::{hash, verify, DEFAULT_COST};
 use std::env;
 use jsonwebtoken::{encode, decode, Header, EncodingKey, DecodingKey};
@@ -57,8 +57,7 @@ impl User {
     }
 
     pub fn register(&mut self) -> Result<String, String> {
-        let mut conn = get_pool()
-            .get_conn()
+        let mut conn = get_conn()
             .map_err(|e| format!("Database connection failed: {}", e))?;
 
         let exists: Option<u64> = conn
@@ -83,8 +82,7 @@ impl User {
     }
 
     pub fn login(&self) -> Result<String, String> {
-        let mut conn = get_pool()
-            .get_conn()
+        let mut conn = get_conn()
             .map_err(|e| format!("Database connection failed: {}", e))?;
 
         let result: Option<String> = conn
@@ -118,8 +116,7 @@ impl User {
     }
 
     pub fn new_solve(&mut self, problem_id: u64) -> Result<(), String> {
-        let mut conn = get_pool()
-            .get_conn()
+        let mut conn = get_conn()
             .map_err(|e| format!("Database connection failed: {}", e))?;
 
         if !self.solves.contains(&problem_id) {
@@ -145,8 +142,7 @@ impl User {
     }
 
     pub fn get_user_by_username(username: &str) -> Result<User, String> {
-        let mut conn = get_pool()
-            .get_conn()
+        let mut conn = get_conn()
             .map_err(|e| format!("Database connection failed: {}", e))?;
 
         let (password, solves_json): (String, String) = conn
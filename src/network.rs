@@ -1,14 +1,21 @@
 use std::collections::HashMap;
-use std::env;
+use std::net::IpAddr;
+use serde_json::{json, Value};
+use crate::headers::HeaderMap;
+use crate::server::Socket;
+use std::str::FromStr;
+use std::sync::mpsc::Receiver;
+use std::time::Instant;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Method {
     GET,
     POST,
     PUT,
     DELETE,
     UPDATE,
-    OPTIONS
+    OPTIONS,
+    HEAD,
 }
 
 impl Method {
@@ -20,29 +27,50 @@ impl Method {
             "DELETE" => Some(Method::DELETE),
             "UPDATE" => Some(Method::UPDATE),
             "OPTIONS" => Some(Method::OPTIONS),
+            "HEAD" => Some(Method::HEAD),
             _ => None,
         }
     }
-    
-    pub fn to_string(&self) -> String {
+
+    pub fn as_str(&self) -> &'static str {
         match self {
-            Method::GET => "GET".to_string(),
-            Method::POST => "POST".to_string(),
-            Method::PUT => "PUT".to_string(),
-            Method::DELETE => "DELETE".to_string(),
-            Method::UPDATE => "UPDATE".to_string(),
-            Method::OPTIONS => "OPTIONS".to_string(),
+            Method::GET => "GET",
+            Method::POST => "POST",
+            Method::PUT => "PUT",
+            Method::DELETE => "DELETE",
+            Method::UPDATE => "UPDATE",
+            Method::OPTIONS => "OPTIONS",
+            Method::HEAD => "HEAD",
         }
     }
 }
 
+impl std::fmt::Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 #[derive(Debug)]
 pub struct Request {
     method: Method,
+    /// The request target exactly as sent, query string included.
+    target: String,
+    /// Percent-decoded path, used for routing.
     path: String,
+    query: Vec<(String, String)>,
     version: String,
-    headers: HashMap<String, String>,
+    headers: HeaderMap,
     body: String,
+    /// Username of the authenticated caller, filled in by the `Auth` middleware.
+    user: Option<String>,
+    /// Peer address, or the forwarding proxy's idea of it; `None` on a Unix socket.
+    client_ip: Option<IpAddr>,
+    /// Set by the `Logger` middleware and echoed as `X-Request-Id`.
+    request_id: Option<String>,
+    /// Pattern of the route that matched, such as `/problem/:id`, set by the router.
+    route: Option<String>,
+    received_at: Instant,
 }
 
 
@@ -50,52 +78,388 @@ pub struct Request {
 pub struct Response {
     status: u16,
     version: String,
-    headers: HashMap<String, String>,
-    body: String,
+    headers: HeaderMap,
+    body: Vec<u8>,
+    /// Answering a HEAD request: advertise the body's length but don't send it.
+    head_only: bool,
+    /// Events to send after the head, for a `text/event-stream` response.
+    stream: Option<Receiver<Event>>,
+    /// What takes over the connection after a 101 response.
+    upgrade: Option<Upgrade>,
+}
+
+/// Runs the protocol a connection was upgraded to, e.g. a WebSocket session.
+pub struct Upgrade(Box<dyn FnOnce(Socket) + Send>);
+
+impl Upgrade {
+    pub fn run(self, socket: Socket) {
+        (self.0)(socket)
+    }
+}
+
+impl std::fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Upgrade")
+    }
+}
+
+/// One server-sent event, see `Response::event_stream`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub data: String,
+}
+
+impl Event {
+    pub fn new(name: &str, data: impl Into<String>) -> Event {
+        Event {
+            id: None,
+            name: Some(name.to_string()),
+            data: data.into(),
+        }
+    }
+
+    pub fn with_id(mut self, id: impl Into<String>) -> Event {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// The event in `text/event-stream` framing. Each line of the data gets its
+    /// own `data:` field, which the client joins back together.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = String::new();
+        if let Some(id) = &self.id {
+            out.push_str(&format!("id: {}\n", id));
+        }
+        if let Some(name) = &self.name {
+            out.push_str(&format!("event: {}\n", name));
+        }
+        for line in self.data.split('\n') {
+            out.push_str(&format!("data: {}\n", line.trim_end_matches('\r')));
+        }
+        out.push('\n');
+        out.into_bytes()
+    }
 }
 
 pub const VERSION: &str = "HTTP/1.1";
 
+/// How much of a request we're willing to buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_body_size: usize,
+    /// The request line and headers together, excluding the blank line after them.
+    pub max_header_size: usize,
+    pub max_header_count: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_body_size: 1024 * 1024,
+            max_header_size: 8 * 1024,
+            max_header_count: 100,
+        }
+    }
+}
+
+/// Every way a client can send us something that isn't a request we can serve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    InvalidUtf8,
+    MalformedRequestLine,
+    InvalidPercentEncoding,
+    UnsupportedVersion,
+    MalformedHeader,
+    InvalidContentLength,
+    ConflictingFraming,
+    /// `Transfer-Encoding` whose last coding isn't `chunked`, leaving the
+    /// body's end unknown.
+    UnsupportedTransferEncoding,
+    MalformedChunk,
+    PayloadTooLarge,
+    HeadersTooLarge,
+    TooManyHeaders,
+    /// A well-formed method we don't implement, such as `PATCH`.
+    UnknownMethod,
+}
+
+impl ParseError {
+    pub fn status(&self) -> u16 {
+        match self {
+            ParseError::UnsupportedVersion => 505,
+            ParseError::PayloadTooLarge => 413,
+            ParseError::HeadersTooLarge | ParseError::TooManyHeaders => 431,
+            ParseError::UnknownMethod => 501,
+            _ => 400,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            ParseError::InvalidUtf8 => "Request is not valid UTF-8",
+            ParseError::MalformedRequestLine => "Malformed request line",
+            ParseError::InvalidPercentEncoding => "Invalid percent-encoding in request target",
+            ParseError::UnsupportedVersion => "HTTP version not supported",
+            ParseError::MalformedHeader => "Malformed header",
+            ParseError::InvalidContentLength => "Invalid Content-Length",
+            ParseError::ConflictingFraming => "Both Content-Length and Transfer-Encoding are set",
+            ParseError::UnsupportedTransferEncoding => "Transfer-Encoding must end in chunked",
+            ParseError::MalformedChunk => "Malformed chunked body",
+            ParseError::PayloadTooLarge => "Payload too large",
+            ParseError::HeadersTooLarge => "Request headers too large",
+            ParseError::TooManyHeaders => "Too many request headers",
+            ParseError::UnknownMethod => "Method not implemented",
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+/// Decodes `%XX` escapes, and `+` as a space when `plus_as_space` is set
+/// (query strings use form encoding, paths don't).
+pub fn percent_decode(raw: &str, plus_as_space: bool) -> Result<String, ParseError> {
+    let bytes = raw.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3).ok_or(ParseError::InvalidPercentEncoding)?;
+                let hex = std::str::from_utf8(hex).map_err(|_| ParseError::InvalidPercentEncoding)?;
+                let byte = u8::from_str_radix(hex, 16).map_err(|_| ParseError::InvalidPercentEncoding)?;
+                decoded.push(byte);
+                i += 3;
+            },
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                i += 1;
+            },
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            },
+        }
+    }
+
+    String::from_utf8(decoded).map_err(|_| ParseError::InvalidUtf8)
+}
+
+/// Splits a request target into its decoded path and query pairs. Keys without
+/// `=` get an empty value; repeated keys are kept in order.
+fn split_target(target: &str) -> Result<(String, Vec<(String, String)>), ParseError> {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    // Fragments are never sent by well-behaved clients; drop one if present.
+    let query = query.split('#').next().unwrap_or("");
+
+    let mut pairs = Vec::new();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        pairs.push((percent_decode(key, true)?, percent_decode(value, true)?));
+    }
+
+    Ok((percent_decode(path, false)?, pairs))
+}
+
+/// Splits a `Name: value` line. Whitespace around the value is optional, but the
+/// name itself may not contain any.
+fn parse_header_line(line: &str) -> Result<(&str, &str), ParseError> {
+    let (name, value) = line.split_once(':').ok_or(ParseError::MalformedHeader)?;
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(ParseError::MalformedHeader);
+    }
+    Ok((name, value.trim_matches([' ', '\t'])))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Whether `raw` holds a complete request head, i.e. only the body can be outstanding.
+pub fn head_received(raw: &[u8]) -> bool {
+    find(raw, b"\r\n\r\n").is_some()
+}
+
+/// Decodes a chunked body, returning it with the number of bytes consumed
+/// (trailers included), or `None` if the final chunk hasn't arrived yet.
+fn decode_chunked(raw: &[u8], max_body_size: usize) -> Result<Option<(Vec<u8>, usize)>, ParseError> {
+    let mut body = Vec::new();
+    let mut pos = 0;
+
+    loop {
+        let line_end = match find(&raw[pos..], b"\r\n") {
+            Some(end) => pos + end,
+            None => return Ok(None),
+        };
+        let size_line = std::str::from_utf8(&raw[pos..line_end]).map_err(|_| ParseError::MalformedChunk)?;
+        // Chunk extensions after ';' carry nothing we use.
+        let size_hex = size_line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_hex, 16).map_err(|_| ParseError::MalformedChunk)?;
+        pos = line_end + 2;
+
+        if size == 0 {
+            // Skip optional trailer fields up to the terminating empty line.
+            loop {
+                let line_end = match find(&raw[pos..], b"\r\n") {
+                    Some(end) => pos + end,
+                    None => return Ok(None),
+                };
+                let empty = line_end == pos;
+                pos = line_end + 2;
+                if empty {
+                    return Ok(Some((body, pos)));
+                }
+            }
+        }
+
+        if body.len().saturating_add(size) > max_body_size {
+            return Err(ParseError::PayloadTooLarge);
+        }
+        if raw.len() - pos < size + 2 {
+            return Ok(None);
+        }
+        if &raw[pos + size..pos + size + 2] != b"\r\n" {
+            return Err(ParseError::MalformedChunk);
+        }
+        body.extend_from_slice(&raw[pos..pos + size]);
+        pos += size + 2;
+    }
+}
+
 impl Request {
-    pub fn new(method: String, path: String, version: String, headers: HashMap<String, String>, body: String) -> Self {
+    pub fn new(method: String, target: String, version: String, headers: HashMap<String, String>, body: String) -> Self {
+        let (path, query) = split_target(&target).unwrap_or_else(|_| (target.clone(), Vec::new()));
         Self {
             method: Method::from_str(&method).unwrap_or(Method::GET),
+            target,
             path,
+            query,
             version,
-            headers,
+            headers: HeaderMap::from(headers),
             body,
+            user: None,
+            client_ip: None,
+            request_id: None,
+            route: None,
+            received_at: Instant::now(),
         }
     }
 
-    pub fn parse(raw: &str) -> Result<Self, &'static str> {
-        let mut lines = raw.split("\r\n");
+    /// A complete request under the default limits, for tests.
+    #[cfg(test)]
+    pub fn from_raw(raw: &str) -> Request {
+        Request::parse(raw.as_bytes(), &Limits::default()).unwrap().unwrap().0
+    }
 
-        let request_line = lines.next().ok_or("Missing request line")?;
+    /// Parses one request from the front of `raw`.
+    ///
+    /// Returns `Ok(None)` while more bytes are needed, otherwise the request together
+    /// with the number of bytes it occupied. The body is framed by `Content-Length`
+    /// or `Transfer-Encoding: chunked`; the head and body must both fit `limits`.
+    pub fn parse(raw: &[u8], limits: &Limits) -> Result<Option<(Self, usize)>, ParseError> {
+        let head_end = match find(raw, b"\r\n\r\n") {
+            Some(pos) => pos,
+            // Without this a client could keep us buffering a header forever.
+            None if raw.len() > limits.max_header_size + 3 => return Err(ParseError::HeadersTooLarge),
+            None => return Ok(None),
+        };
+        if head_end > limits.max_header_size {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        let head = std::str::from_utf8(&raw[..head_end]).map_err(|_| ParseError::InvalidUtf8)?;
+        let mut lines = head.split("\r\n");
+
+        let request_line = lines.next().ok_or(ParseError::MalformedRequestLine)?;
         let mut request_parts = request_line.split_whitespace();
-        let method = request_parts.next().ok_or("Missing method")?.to_string();
-        let path = request_parts.next().ok_or("Missing path")?.to_string();
-        let version = request_parts.next().ok_or("Missing version")?.to_string();
+        let method = request_parts.next().ok_or(ParseError::MalformedRequestLine)?;
+        let target = request_parts.next().ok_or(ParseError::MalformedRequestLine)?.to_string();
+        let version = request_parts.next().ok_or(ParseError::MalformedRequestLine)?.to_string();
+        if request_parts.next().is_some() || !target.starts_with('/') && target != "*" {
+            return Err(ParseError::MalformedRequestLine);
+        }
+        match version.as_str() {
+            "HTTP/1.0" | "HTTP/1.1" => {},
+            v if v.starts_with("HTTP/") => return Err(ParseError::UnsupportedVersion),
+            _ => return Err(ParseError::MalformedRequestLine),
+        }
+        let method = Method::from_str(method).ok_or(ParseError::UnknownMethod)?;
 
-        let mut headers = HashMap::new();
-        for line in &mut lines {
-            if line.is_empty() {
-                break;
+        let mut headers = HeaderMap::new();
+        for (count, line) in lines.enumerate() {
+            if count == limits.max_header_count {
+                return Err(ParseError::TooManyHeaders);
             }
-            let mut parts = line.splitn(2, ": ");
-            let key = parts.next().ok_or("Malformed header")?.to_string();
-            let value = parts.next().ok_or("Malformed header")?.to_string();
-            headers.insert(key, value);
+            let (name, value) = parse_header_line(line)?;
+            headers.append(name, value);
         }
 
-        let body = lines.collect::<Vec<_>>().join("\r\n").trim().replace("\0","");
+        let body_start = head_end + 4;
+        let lengths = headers.get_all("Content-Length");
+        if headers.contains("Transfer-Encoding") && !lengths.is_empty() {
+            return Err(ParseError::ConflictingFraming);
+        }
+        // Repeated Content-Length headers are only acceptable if they agree.
+        if lengths.windows(2).any(|pair| pair[0] != pair[1]) {
+            return Err(ParseError::InvalidContentLength);
+        }
 
-        Ok(Self {
-            method: Method::from_str(&method).unwrap_or(Method::GET),
-            path,
-            version,
-            headers,
-            body,
-        })
+        // Only a final `chunked` tells where the body ends; guessing would let
+        // the rest of it pass for the next request on the connection.
+        let codings = headers.get_all("Transfer-Encoding");
+        let chunked = match codings.iter().flat_map(|value| value.split(',')).last() {
+            Some(last) if last.trim().eq_ignore_ascii_case("chunked") => true,
+            Some(_) => return Err(ParseError::UnsupportedTransferEncoding),
+            None => false,
+        };
+
+        let (body, consumed) = if chunked {
+            match decode_chunked(&raw[body_start..], limits.max_body_size)? {
+                Some((body, used)) => (body, body_start + used),
+                None => return Ok(None),
+            }
+        } else if let Some(length) = lengths.first() {
+            // `parse` would also take a sign, as in `+5`.
+            if length.is_empty() || !length.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ParseError::InvalidContentLength);
+            }
+            let length: usize = length.parse().map_err(|_| ParseError::InvalidContentLength)?;
+            if length > limits.max_body_size {
+                return Err(ParseError::PayloadTooLarge);
+            }
+            if raw.len() - body_start < length {
+                return Ok(None);
+            }
+            (raw[body_start..body_start + length].to_vec(), body_start + length)
+        } else {
+            (Vec::new(), body_start)
+        };
+
+        let body = String::from_utf8(body).map_err(|_| ParseError::InvalidUtf8)?;
+        let (path, query) = split_target(&target)?;
+
+        Ok(Some((
+            Self {
+                method,
+                target,
+                path,
+                query,
+                version,
+                headers,
+                body,
+                user: None,
+                client_ip: None,
+                request_id: None,
+                route: None,
+                received_at: Instant::now(),
+            },
+            consumed,
+        )))
     }
 
     pub fn get_method(&self) -> &Method {
@@ -106,21 +470,98 @@ impl Request {
         &self.path
     }
 
+    pub fn get_target(&self) -> &String {
+        &self.target
+    }
+
+    /// First value of a query parameter, as sent.
+    pub fn get_query_raw(&self, key: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// First value of a query parameter, parsed; `None` if absent or unparsable.
+    pub fn get_query<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.get_query_raw(key)?.parse().ok()
+    }
+
+    /// Every value given for a repeated query parameter, in order.
+    #[cfg(test)]
+    pub fn get_query_all(&self, key: &str) -> Vec<&str> {
+        self.query
+            .iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
     pub fn get_version(&self) -> &String {
         &self.version
     }
 
-    pub fn get_header(&self, key: &str) -> Option<&String> {
+    pub fn get_header(&self, key: &str) -> Option<&str> {
         self.headers.get(key)
     }
 
+    pub fn get_headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
     pub fn get_body(&self) -> &String {
         &self.body
     }
 
+    pub fn get_user(&self) -> Option<&String> {
+        self.user.as_ref()
+    }
+
+    pub fn set_user(&mut self, username: String) {
+        self.user = Some(username);
+    }
+
+    pub fn get_client_ip(&self) -> Option<IpAddr> {
+        self.client_ip
+    }
+
+    pub fn set_client_ip(&mut self, ip: Option<IpAddr>) {
+        self.client_ip = ip;
+    }
+
+    pub fn get_request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+
+    pub fn set_request_id(&mut self, id: String) {
+        self.request_id = Some(id);
+    }
+
+    pub fn get_route(&self) -> Option<&str> {
+        self.route.as_deref()
+    }
+
+    pub fn set_route(&mut self, route: String) {
+        self.route = Some(route);
+    }
+
+    pub fn received_at(&self) -> Instant {
+        self.received_at
+    }
+
+    /// Whether the client wants the connection kept open after this request:
+    /// HTTP/1.1 persists unless told `close`, HTTP/1.0 only on `keep-alive`.
+    pub fn keep_alive(&self) -> bool {
+        if self.version == "HTTP/1.0" {
+            self.headers.has_token("Connection", "keep-alive")
+        } else {
+            !self.headers.has_token("Connection", "close")
+        }
+    }
+
     pub fn to_string(&self) -> String {
-        let mut result = format!("{} {} {}\r\n", self.method.to_string(), self.path, self.version);
-        for (key, value) in &self.headers {
+        let mut result = format!("{} {} {}\r\n", self.method, self.target, self.version);
+        for (key, value) in self.headers.iter() {
             result.push_str(&format!("{}: {}\r\n", key, value));
         }
         result.push_str("\r\n");
@@ -130,81 +571,449 @@ impl Request {
 }
 
 impl Response {
-    pub fn new(status: u16, mut headers: HashMap<String, String>, body: String, version: String) -> Self {
-        // Add default CORS headers
-        headers.insert("Access-Control-Allow-Credentials".to_string(), "true".to_string());
-        headers.insert("Access-Control-Allow-Methods".to_string(), 
-            "GET, POST, PUT, DELETE, OPTIONS".to_string());
-        headers.insert("Access-Control-Allow-Headers".to_string(), 
-            "Content-Type, Authorization".to_string());
-        
-        // Handle Origin
-        if let Ok(allowed_origins) = env::var("ALLOWED_ORIGINS") {
-            headers.insert("Access-Control-Allow-Origin".to_string(), allowed_origins);
-        } else {
-            headers.insert("Access-Control-Allow-Origin".to_string(), 
-                "http://localhost:3000".to_string());
-        }
-
+    pub fn new(status: u16, headers: HashMap<String, String>, body: String, version: String) -> Self {
         Self {
             version,
             status,
-            headers,
-            body,
+            headers: HeaderMap::from(headers),
+            body: body.into_bytes(),
+            head_only: false,
+            stream: None,
+            upgrade: None,
         }
     }
 
+    /// A JSON response with the matching `Content-Type`.
+    pub fn json(status: u16, body: Value) -> Self {
+        Self::new(status, HashMap::new(), body.to_string(), VERSION.into())
+            .with_header("Content-Type", "application/json")
+    }
+
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Self::new(status, HashMap::new(), body.into(), VERSION.into())
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+    }
+
+    /// A binary body, such as a file, of the given type.
+    pub fn bytes(status: u16, content_type: &str, body: Vec<u8>) -> Self {
+        let mut response = Self::empty(status).with_header("Content-Type", content_type);
+        response.body = body;
+        response
+    }
+
+    /// A `text/event-stream` response that sends each event from `events` as
+    /// it arrives, and ends along with the channel. The stream is delimited by
+    /// closing the connection, so it never carries a `Content-Length`.
+    pub fn event_stream(events: Receiver<Event>) -> Self {
+        let mut response = Self::empty(200)
+            .with_header("Content-Type", "text/event-stream")
+            .with_header("Cache-Control", "no-cache")
+            // Stops nginx from buffering the stream.
+            .with_header("X-Accel-Buffering", "no");
+        response.stream = Some(events);
+        response
+    }
+
+    /// A 101 response switching the connection to `protocol`. Once it has been
+    /// sent, `session` gets the connection to itself.
+    pub fn switching_protocols(protocol: &str, session: impl FnOnce(Socket) + Send + 'static) -> Self {
+        let mut response = Self::empty(101)
+            .with_header("Upgrade", protocol)
+            .with_header("Connection", "Upgrade");
+        response.upgrade = Some(Upgrade(Box::new(session)));
+        response
+    }
+
+    pub fn empty(status: u16) -> Self {
+        Self::new(status, HashMap::new(), String::new(), VERSION.into())
+    }
+
+    /// `{"message": ...}` with the given status, the shape every error takes.
+    pub fn error(status: u16, message: impl std::fmt::Display) -> Self {
+        Self::json(status, json!({ "message": message.to_string() }))
+    }
+
+    pub fn ok(body: Value) -> Self {
+        Self::json(200, body)
+    }
+
+    pub fn created(body: Value) -> Self {
+        Self::json(201, body)
+    }
+
+    pub fn bad_request(message: impl std::fmt::Display) -> Self {
+        Self::error(400, message)
+    }
+
+    pub fn unauthorized(message: impl std::fmt::Display) -> Self {
+        Self::error(401, message)
+    }
+
+    pub fn not_found(message: impl std::fmt::Display) -> Self {
+        Self::error(404, message)
+    }
+
+    pub fn internal_error(message: impl std::fmt::Display) -> Self {
+        Self::error(500, message)
+    }
+
+    pub fn with_header(mut self, key: &str, value: &str) -> Self {
+        self.headers.insert(key, value);
+        self
+    }
+
     pub fn parse(raw: &str) -> Result<Self, &'static str> {
         let mut lines = raw.split("\r\n");
 
         let status_line = lines.next().ok_or("Missing status line")?;
         let mut status_parts = status_line.split_whitespace();
         let version = status_parts.next().ok_or("Missing version")?.to_string();
-        let status = status_parts.next().ok_or("Missing status")?.parse::<u16>().unwrap();
+        let status = status_parts.next().ok_or("Missing status")?.parse::<u16>().map_err(|_| "Invalid status")?;
 
-        let mut headers = HashMap::new();
+        let mut headers = HeaderMap::new();
         for line in &mut lines {
             if line.is_empty() {
                 break;
             }
-            let mut parts = line.splitn(2, ": ");
-            let key = parts.next().ok_or("Malformed header")?.to_string();
-            let value = parts.next().ok_or("Malformed header")?.to_string();
-            headers.insert(key, value);
+            let (name, value) = parse_header_line(line).map_err(|e| e.message())?;
+            headers.append(name, value);
         }
 
-        let body = lines.collect::<Vec<_>>().join("\r\n");
+        let body = lines.collect::<Vec<_>>().join("\r\n").into_bytes();
 
         Ok(Self {
             status,
             version,
             headers,
             body,
+            head_only: false,
+            stream: None,
+            upgrade: None,
         })
     }
 
-    pub fn to_string(&self) -> String {
-        let mut result = format!("{} {} {}\r\n", self.version, self.status, Self::status_reason(self.status));
-        for (key, value) in &self.headers {
-            result.push_str(&format!("{}: {}\r\n", key, value));
+    pub fn get_status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn set_status(&mut self, status: u16) {
+        self.status = status;
+    }
+
+    pub fn get_header(&self, key: &str) -> Option<&str> {
+        self.headers.get(key)
+    }
+
+    pub fn set_header(&mut self, key: &str, value: &str) {
+        self.headers.insert(key, value);
+    }
+
+    /// Adds a header without replacing earlier ones of the same name.
+    #[cfg(test)]
+    pub fn append_header(&mut self, key: &str, value: &str) {
+        self.headers.append(key, value);
+    }
+
+    /// Adds `name` to the `Vary` list unless it's already there.
+    pub fn add_vary(&mut self, name: &str) {
+        if self.headers.has_token("Vary", name) {
+            return;
         }
-        result.push_str("\r\n");
-        result.push_str(&self.body);
-        result
+        let vary = match self.headers.get("Vary") {
+            Some(existing) => format!("{}, {}", existing, name),
+            None => name.to_string(),
+        };
+        self.headers.insert("Vary", &vary);
+    }
+
+    /// Turns a GET response into the matching HEAD response.
+    pub fn without_body(mut self) -> Self {
+        self.head_only = true;
+        self.stream = None;
+        self
     }
 
+    /// A HEAD response for a body that was never loaded, `length` bytes long.
+    pub fn with_head_length(mut self, length: usize) -> Self {
+        self.head_only = true;
+        self.with_header("Content-Length", &length.to_string())
+    }
+
+    pub fn is_stream(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Hands the event stream over to whoever writes the response out.
+    pub fn take_stream(&mut self) -> Option<Receiver<Event>> {
+        self.stream.take()
+    }
+
+    pub fn take_upgrade(&mut self) -> Option<Upgrade> {
+        self.upgrade.take()
+    }
+
+    pub fn get_body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Replaces the body, e.g. with an encoded form of itself.
+    pub fn set_body(&mut self, body: Vec<u8>) {
+        self.body = body;
+    }
+
+    /// The response as it goes on the wire.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut head = format!("{} {} {}\r\n", self.version, self.status, Self::status_reason(self.status));
+        for (key, value) in self.headers.iter() {
+            if key.eq_ignore_ascii_case("Content-Length") {
+                continue;
+            }
+            head.push_str(&format!("{}: {}\r\n", key, value));
+        }
+        // 1xx, 204 and 304 responses never carry a body, so there is nothing to frame.
+        let bodiless = self.status < 200 || matches!(self.status, 204 | 304);
+        if !bodiless && !self.is_stream() {
+            // Only a HEAD answer can stand in for a body it doesn't have.
+            let length = match self.headers.get("Content-Length") {
+                Some(length) if self.head_only => length.to_string(),
+                _ => self.body.len().to_string(),
+            };
+            head.push_str(&format!("Content-Length: {}\r\n", length));
+        }
+        head.push_str("\r\n");
+
+        let mut result = head.into_bytes();
+        if !bodiless && !self.head_only {
+            result.extend_from_slice(&self.body);
+        }
+        result
+    }
 
     fn status_reason(status: u16) -> &'static str {
         match status {
+            101 => "Switching Protocols",
             200 => "OK",
             201 => "Created",
             204 => "No Content",
+            206 => "Partial Content",
+            304 => "Not Modified",
             400 => "Bad Request",
             401 => "Unauthorized",
             403 => "Forbidden",
             404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            409 => "Conflict",
+            413 => "Payload Too Large",
+            416 => "Range Not Satisfiable",
+            422 => "Unprocessable Entity",
+            426 => "Upgrade Required",
+            429 => "Too Many Requests",
+            431 => "Request Header Fields Too Large",
             500 => "Internal Server Error",
+            501 => "Not Implemented",
+            503 => "Service Unavailable",
+            505 => "HTTP Version Not Supported",
             _ => "Unknown",
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: Limits = Limits { max_body_size: 1024, max_header_size: 256, max_header_count: 8 };
+
+    fn parse(raw: &[u8]) -> Result<Option<(Request, usize)>, ParseError> {
+        Request::parse(raw, &LIMITS)
+    }
+
+    #[test]
+    fn parses_content_length_body() {
+        let raw = b"POST /ide HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhelloGET";
+        let (request, used) = parse(raw).unwrap().unwrap();
+        assert_eq!(request.get_body(), "hello");
+        assert_eq!(used, raw.len() - 3);
+    }
+
+    #[test]
+    fn waits_for_incomplete_body() {
+        let raw = b"POST /ide HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello";
+        assert!(parse(raw).unwrap().is_none());
+        assert!(parse(b"GET / HTTP/1.1\r\nHost: x\r\n").unwrap().is_none());
+    }
+
+    #[test]
+    fn parses_chunked_body() {
+        let raw = b"POST /ide HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let (request, used) = parse(raw).unwrap().unwrap();
+        assert_eq!(request.get_body(), "hello world");
+        assert_eq!(used, raw.len());
+    }
+
+    #[test]
+    fn maps_errors_to_statuses() {
+        let cases: [(&[u8], ParseError, u16); 11] = [
+            (b"GET /\r\n\r\n", ParseError::MalformedRequestLine, 400),
+            (b"GET / HTTP/2.0\r\n\r\n", ParseError::UnsupportedVersion, 505),
+            (b"GET / FTP/1.0\r\n\r\n", ParseError::MalformedRequestLine, 400),
+            (b"PATCH /problem/5 HTTP/1.1\r\n\r\n", ParseError::UnknownMethod, 501),
+            (b"GET / HTTP/1.1\r\nbro ken: x\r\n\r\n", ParseError::MalformedHeader, 400),
+            (b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n", ParseError::InvalidContentLength, 400),
+            (b"POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\nhello", ParseError::InvalidContentLength, 400),
+            (b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\nGET /admin HTTP/1.1\r\n\r\n", ParseError::UnsupportedTransferEncoding, 400),
+            (b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\n0\r\n\r\n", ParseError::UnsupportedTransferEncoding, 400),
+            (b"POST / HTTP/1.1\r\nContent-Length: 4096\r\n\r\n", ParseError::PayloadTooLarge, 413),
+            (b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n", ParseError::MalformedChunk, 400),
+        ];
+        for (raw, expected, status) in cases {
+            let err = parse(raw).err().unwrap();
+            assert_eq!(err, expected, "{}", String::from_utf8_lossy(raw));
+            assert_eq!(err.status(), status);
+        }
+    }
+
+    #[test]
+    fn limits_header_size_and_count() {
+        let long = format!("GET / HTTP/1.1\r\nX-Pad: {}\r\n\r\n", "a".repeat(300));
+        assert_eq!(parse(long.as_bytes()).err(), Some(ParseError::HeadersTooLarge));
+        // Rejected before the head is complete, so a slow client can't make us buffer it all.
+        assert_eq!(parse(&long.as_bytes()[..270]).err(), Some(ParseError::HeadersTooLarge));
+        assert!(parse(&long.as_bytes()[..200]).unwrap().is_none());
+
+        let many = format!("GET / HTTP/1.1\r\n{}\r\n", "A: 1\r\n".repeat(9));
+        let err = parse(many.as_bytes()).err().unwrap();
+        assert_eq!(err, ParseError::TooManyHeaders);
+        assert_eq!(err.status(), 431);
+        assert!(parse(format!("GET / HTTP/1.1\r\n{}\r\n", "A: 1\r\n".repeat(8)).as_bytes()).unwrap().is_some());
+    }
+
+    #[test]
+    fn splits_and_decodes_query() {
+        let raw = b"GET /user/j%C3%B6rg/solves?page=2&tag=a+b&tag=c%26d&flag HTTP/1.1\r\n\r\n";
+        let (request, _) = parse(raw).unwrap().unwrap();
+        assert_eq!(request.get_path(), "/user/jörg/solves");
+        assert_eq!(request.get_query::<u32>("page"), Some(2));
+        assert_eq!(request.get_query_all("tag"), vec!["a b", "c&d"]);
+        assert_eq!(request.get_query_raw("flag"), Some(""));
+        assert_eq!(request.get_query::<u32>("missing"), None);
+        assert_eq!(
+            parse(b"GET /a%zz HTTP/1.1\r\n\r\n").err(),
+            Some(ParseError::InvalidPercentEncoding)
+        );
+    }
+
+    #[test]
+    fn headers_are_case_insensitive_and_multi_valued() {
+        let raw = b"GET / HTTP/1.1\r\nHost:foo\r\nauthorization: \t Bearer t \r\nAccept: a\r\naccept: b\r\n\r\n";
+        let (request, _) = parse(raw).unwrap().unwrap();
+        assert_eq!(request.get_header("host"), Some("foo"));
+        assert_eq!(request.get_header("Authorization"), Some("Bearer t"));
+        assert_eq!(request.get_headers().get_all("ACCEPT"), vec!["a", "b"]);
+
+        let mut response = Response::new(200, HashMap::new(), String::new(), VERSION.into());
+        response.append_header("Set-Cookie", "a=1");
+        response.append_header("set-cookie", "b=2");
+        response.set_header("X-Test", "1");
+        response.set_header("x-test", "2");
+        let text = String::from_utf8(response.to_bytes()).unwrap();
+        assert!(text.contains("Set-Cookie: a=1\r\nset-cookie: b=2\r\nX-Test: 2\r\n"));
+    }
+
+    #[test]
+    fn rejects_invalid_utf8() {
+        assert_eq!(parse(b"GET /\xff HTTP/1.1\r\n\r\n").err(), Some(ParseError::InvalidUtf8));
+        assert_eq!(
+            parse(b"POST / HTTP/1.1\r\nContent-Length: 1\r\n\r\n\xff").err(),
+            Some(ParseError::InvalidUtf8)
+        );
+    }
+
+    #[test]
+    fn frames_server_sent_events() {
+        let event = Event::new("running", "{\"test\":1}\nsecond line").with_id("3");
+        assert_eq!(event.to_bytes(), b"id: 3\nevent: running\ndata: {\"test\":1}\ndata: second line\n\n");
+
+        let (_sender, events) = std::sync::mpsc::channel();
+        let text = String::from_utf8(Response::event_stream(events).to_bytes()).unwrap();
+        assert!(text.contains("Content-Type: text/event-stream\r\n"));
+        assert!(!text.contains("Content-Length"));
+    }
+
+    #[test]
+    fn error_responses_are_json() {
+        let response = Response::bad_request("Missing 'code'");
+        assert_eq!(response.get_status(), 400);
+        assert_eq!(response.get_header("Content-Type"), Some("application/json"));
+
+        let raw = String::from_utf8(response.to_bytes()).unwrap();
+        assert!(raw.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(raw.ends_with("\r\n\r\n{\"message\":\"Missing 'code'\"}"));
+
+        let response = Response::empty(204).with_header("Allow", "GET");
+        assert_eq!(response.get_header("Allow"), Some("GET"));
+        assert!(response.get_header("Content-Type").is_none());
+    }
+
+    /// Small xorshift generator so the fuzz cases are reproducible without extra crates.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    fn check_never_panics(raw: &[u8]) {
+        if let Ok(Some((_, used))) = parse(raw) {
+            assert!(used <= raw.len());
+        }
+    }
+
+    #[test]
+    fn fuzz_random_bytes() {
+        let alphabet = b"GETPOST /HTP1.0:\r\n \t0123456789abcdef;chunked\xff\x00";
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        for _ in 0..20_000 {
+            let len = rng.below(128);
+            let raw: Vec<u8> = (0..len)
+                .map(|_| if rng.below(4) == 0 { rng.next() as u8 } else { alphabet[rng.below(alphabet.len())] })
+                .collect();
+            check_never_panics(&raw);
+        }
+    }
+
+    #[test]
+    fn fuzz_mutated_requests() {
+        let seeds: [&[u8]; 3] = [
+            b"POST /ide HTTP/1.1\r\nHost: x\r\nContent-Length: 5\r\n\r\nhello",
+            b"POST /ide HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+            b"GET /problem/1 HTTP/1.0\r\nAuthorization: Bearer t\r\n\r\n",
+        ];
+        let mut rng = Rng(0xdeadbeefcafef00d);
+        for _ in 0..20_000 {
+            let mut raw = seeds[rng.below(seeds.len())].to_vec();
+            for _ in 0..=rng.below(4) {
+                let pos = rng.below(raw.len() + 1);
+                match rng.below(4) {
+                    0 if pos < raw.len() => raw[pos] = rng.next() as u8,
+                    1 if pos < raw.len() => { raw.remove(pos); },
+                    2 => {
+                        raw.insert(pos, b'\n');
+                        raw.insert(pos, b'\r');
+                    },
+                    _ => raw.truncate(pos),
+                }
+            }
+            check_never_panics(&raw);
+        }
+    }
+}
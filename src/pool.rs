@@ -0,0 +1,94 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+use crate::logging;
+use crate::metrics::WORKER_QUEUE_DEPTH;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+static POOL: OnceLock<ThreadPool> = OnceLock::new();
+
+/// Starts the pool that handles requests and the background work they start.
+pub fn init_pool(workers: usize, queue_limit: usize) {
+    if POOL.set(ThreadPool::new(workers, queue_limit)).is_err() {
+        panic!("Worker pool already initialized");
+    }
+}
+
+pub fn get_pool() -> &'static ThreadPool {
+    POOL.get().expect("Worker pool not initialized. Call init_pool() first.")
+}
+
+/// Returned by `ThreadPool::execute` when every worker is busy and the queue is full.
+#[derive(Debug)]
+pub struct PoolFull;
+
+pub struct ThreadPool {
+    sender: SyncSender<Job>,
+    /// Jobs queued or running.
+    pending: Arc<AtomicUsize>,
+}
+
+impl ThreadPool {
+    pub fn new(workers: usize, queue_limit: usize) -> ThreadPool {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_limit);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let pending = Arc::new(AtomicUsize::new(0));
+
+        for id in 0..workers {
+            let receiver = Arc::clone(&receiver);
+            let pending = Arc::clone(&pending);
+            thread::Builder::new()
+                .name(format!("worker-{}", id))
+                .spawn(move || Self::work(receiver, pending))
+                .expect("Failed to spawn worker thread");
+        }
+
+        ThreadPool { sender, pending }
+    }
+
+    /// How many jobs are queued or running; shutdown waits for none.
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    pub fn execute<F>(&self, job: F) -> Result<(), PoolFull>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // Counted before sending, so a worker taking the job right away
+        // never sees it uncounted.
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        WORKER_QUEUE_DEPTH.inc(&[]);
+        match self.sender.try_send(Box::new(job)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.pending.fetch_sub(1, Ordering::SeqCst);
+                WORKER_QUEUE_DEPTH.dec(&[]);
+                Err(PoolFull)
+            },
+        }
+    }
+
+    fn work(receiver: Arc<Mutex<Receiver<Job>>>, pending: Arc<AtomicUsize>) {
+        loop {
+            let job = match receiver.lock() {
+                Ok(rx) => match rx.recv() {
+                    Ok(job) => job,
+                    Err(_) => return,
+                },
+                Err(_) => return,
+            };
+            WORKER_QUEUE_DEPTH.dec(&[]);
+
+            // A panicking handler must not take the worker down with it.
+            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                logging::error(&format!("{} recovered from a panicking job", thread::current().name().unwrap_or("worker")));
+            }
+            pending.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
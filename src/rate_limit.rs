@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How many requests a client may make per period, e.g. `10/min`. They may
+/// come in one burst; after that they are allowed through evenly spread out.
+/// `off` (or a count of 0) means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub requests: u32,
+    pub per: Duration,
+}
+
+impl Rate {
+    pub fn unlimited(&self) -> bool {
+        self.requests == 0
+    }
+
+    /// Tokens regained per second.
+    fn refill(&self) -> f64 {
+        f64::from(self.requests) / self.per.as_secs_f64()
+    }
+}
+
+impl FromStr for Rate {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Rate, ()> {
+        if s.eq_ignore_ascii_case("off") {
+            return Ok(Rate { requests: 0, per: Duration::from_secs(1) });
+        }
+        let (requests, per) = s.split_once('/').ok_or(())?;
+        let secs = match per.trim().to_ascii_lowercase().as_str() {
+            "s" | "sec" | "second" => 1,
+            "m" | "min" | "minute" => 60,
+            "h" | "hour" => 3600,
+            "d" | "day" => 86400,
+            _ => return Err(()),
+        };
+        Ok(Rate {
+            requests: requests.trim().parse().map_err(|_| ())?,
+            per: Duration::from_secs(secs),
+        })
+    }
+}
+
+/// Most client keys a limiter keeps a bucket for.
+const MAX_BUCKETS: usize = 16 * 1024;
+
+/// The key an address is limited by. An IPv6 client usually has a whole /64
+/// to pick addresses from, so that is what counts as one client.
+pub fn client_key(ip: IpAddr) -> String {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => {
+            let prefix = u128::from(ip) & !((1u128 << 64) - 1);
+            format!("{}/64", Ipv6Addr::from(prefix))
+        },
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets for one rate, one per client key.
+pub struct Limiter {
+    rate: Rate,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl Limiter {
+    pub fn new(rate: Rate) -> Limiter {
+        Limiter {
+            rate,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Spends a token for `key`. When none is left, returns how long until
+    /// one will be, in whole seconds as `Retry-After` wants.
+    pub fn check(&self, key: &str) -> Result<(), u64> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> Result<(), u64> {
+        if self.rate.unlimited() {
+            return Ok(());
+        }
+        let capacity = f64::from(self.rate.requests);
+        let refill = self.rate.refill();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(key) {
+            evict(&mut buckets, now, capacity, refill);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket { tokens: capacity, updated: now });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / refill).ceil().max(1.0) as u64)
+        }
+    }
+}
+
+/// Makes room for new keys. Buckets that have filled up again are no
+/// different from new ones, so they go first; if that's not enough, so do the
+/// longest idle. Either way an eighth of the room is freed, so a flood of new
+/// keys only pays for a sweep now and then.
+fn evict(buckets: &mut HashMap<String, Bucket>, now: Instant, capacity: f64, refill: f64) {
+    buckets.retain(|_, b| b.tokens + now.saturating_duration_since(b.updated).as_secs_f64() * refill < capacity);
+
+    let keep = MAX_BUCKETS - MAX_BUCKETS / 8;
+    if buckets.len() > keep {
+        let mut updated: Vec<Instant> = buckets.values().map(|b| b.updated).collect();
+        let index = updated.len() - keep;
+        let (_, cutoff, _) = updated.select_nth_unstable(index);
+        let cutoff = *cutoff;
+        buckets.retain(|_, b| b.updated >= cutoff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!("5/min".parse(), Ok(Rate { requests: 5, per: Duration::from_secs(60) }));
+        assert_eq!("2 / s".parse(), Ok(Rate { requests: 2, per: Duration::from_secs(1) }));
+        assert!("off".parse::<Rate>().unwrap().unlimited());
+        assert_eq!("5".parse::<Rate>(), Err(()));
+        assert_eq!("5/fortnight".parse::<Rate>(), Err(()));
+    }
+
+    #[test]
+    fn allows_a_burst_then_refills() {
+        let limiter = Limiter::new("30/min".parse().unwrap());
+        let start = Instant::now();
+
+        for _ in 0..30 {
+            assert_eq!(limiter.check_at("1.2.3.4", start), Ok(()));
+        }
+        assert_eq!(limiter.check_at("1.2.3.4", start), Err(2));
+        // Other clients have their own buckets.
+        assert_eq!(limiter.check_at("5.6.7.8", start), Ok(()));
+
+        assert_eq!(limiter.check_at("1.2.3.4", start + Duration::from_secs(1)), Err(1));
+        assert_eq!(limiter.check_at("1.2.3.4", start + Duration::from_secs(2)), Ok(()));
+        assert_eq!(limiter.check_at("1.2.3.4", start + Duration::from_secs(2)), Err(2));
+    }
+
+    #[test]
+    fn keys_ipv6_clients_by_their_64() {
+        let key = |ip: &str| client_key(ip.parse().unwrap());
+        assert_eq!(key("2001:db8:1:2:aaaa::1"), "2001:db8:1:2::/64");
+        assert_eq!(key("2001:db8:1:2:bbbb::2"), "2001:db8:1:2::/64");
+        assert_ne!(key("2001:db8:1:3::1"), key("2001:db8:1:2::1"));
+        assert_eq!(key("::ffff:1.2.3.4"), "1.2.3.4");
+        assert_eq!(key("1.2.3.4"), "1.2.3.4");
+    }
+
+    #[test]
+    fn caps_buckets_by_evicting_the_longest_idle() {
+        let limiter = Limiter::new("1/min".parse().unwrap());
+        let start = Instant::now();
+        for i in 0..MAX_BUCKETS {
+            assert_eq!(limiter.check_at(&i.to_string(), start + Duration::from_millis(i as u64)), Ok(()));
+        }
+        let now = start + Duration::from_millis(MAX_BUCKETS as u64);
+        assert_eq!(limiter.check_at("new", now), Ok(()));
+
+        let buckets = limiter.buckets.lock().unwrap().len();
+        assert!(buckets <= MAX_BUCKETS - MAX_BUCKETS / 8 + 1, "{} buckets", buckets);
+        // The most recent clients are still limited; the longest idle start over.
+        assert!(limiter.check_at(&(MAX_BUCKETS - 1).to_string(), now).is_err());
+        assert_eq!(limiter.check_at("0", now), Ok(()));
+    }
+}
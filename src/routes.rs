@@ -1,61 +1,415 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::Arc;
+
 use crate::views;
+use crate::config::get_config;
+use crate::metrics;
+use crate::middleware::{self, Auth, Compress, Cors, Logger, Middleware, RateLimit, Timing};
 use crate::network::{Request, Response, Method};
+use crate::static_files::StaticFiles;
+
+pub type Handler = fn(&Request, &Params) -> Response;
+
+/// Path parameters captured by a route pattern such as `/problem/:id`.
+#[derive(Debug, Default)]
+pub struct Params {
+    values: Vec<(String, String)>,
+}
+
+impl Params {
+    pub fn get<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.raw(name)?.parse().ok()
+    }
 
-pub struct Router;
+    pub fn raw(&self, name: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+struct Route {
+    method: Method,
+    /// As registered, used to label metrics.
+    pattern: String,
+    segments: Vec<Segment>,
+    handler: Handler,
+    middleware: Vec<Arc<dyn Middleware>>,
+    /// Replaces the router's default CORS policy for this route.
+    cors: Option<Arc<Cors>>,
+}
+
+impl Route {
+    fn matches(&self, parts: &[&str]) -> Option<Params> {
+        if parts.len() != self.segments.len() {
+            return None;
+        }
+
+        let mut params = Params::default();
+        for (segment, part) in self.segments.iter().zip(parts) {
+            match segment {
+                Segment::Literal(literal) if literal == part => {},
+                Segment::Literal(_) => return None,
+                Segment::Param(name) => params.values.push((name.clone(), part.to_string())),
+            }
+        }
+        Some(params)
+    }
+}
+
+/// Splits a path into its non-empty segments, so `/signup`, `/signup/` and
+/// `//signup` all route the same way.
+fn split_path(path: &str) -> Vec<&str> {
+    path.split('/').filter(|part| !part.is_empty()).collect()
+}
+
+fn chain(middleware: &[Arc<dyn Middleware>]) -> Vec<&dyn Middleware> {
+    middleware.iter().map(|m| m.as_ref()).collect()
+}
+
+pub struct Router {
+    routes: Vec<Route>,
+    /// Wraps every request, including 404/405 answers.
+    middleware: Vec<Arc<dyn Middleware>>,
+    /// The CORS policy for routes without their own, and for 404s.
+    cors: Arc<Cors>,
+    /// Tried for GET and HEAD requests that match no route.
+    static_files: Option<StaticFiles>,
+}
+
+/// Registers routes that share a middleware stack, see `Router::group`.
+pub struct Group<'a> {
+    router: &'a mut Router,
+    middleware: Vec<Arc<dyn Middleware>>,
+    cors: Option<Arc<Cors>>,
+}
+
+impl Group<'_> {
+    /// Gives the group's routes their own CORS policy. Only routes added after
+    /// this call get it.
+    pub fn cors(&mut self, cors: Cors) -> &mut Self {
+        self.cors = Some(Arc::new(cors));
+        self
+    }
+
+    pub fn add(&mut self, method: Method, pattern: &str, handler: Handler) -> &mut Self {
+        self.router.add_route(method, pattern, handler, self.middleware.clone(), self.cors.clone());
+        self
+    }
+}
 
 impl Router {
     pub fn new() -> Router {
-        Router {}
+        let config = get_config();
+        let mut router = Router::with_cors(Cors::from_config());
+        router
+            .wrap(Arc::new(Logger))
+            .wrap(Arc::new(Compress { min_size: config.compression_min_size }))
+            .wrap(Arc::new(Timing));
+
+        router.add(Method::POST, "/signup", views::signup);
+        router
+            .group(vec![Arc::new(RateLimit::new(config.rate_limit_login))])
+            .add(Method::POST, "/login", views::login);
+        // Batch and interactive runs share one budget.
+        router
+            .group(vec![Arc::new(RateLimit::new(config.rate_limit_ide))])
+            .add(Method::POST, "/ide", views::ide)
+            .add(Method::GET, "/ide/ws", views::ide_ws);
+
+        router
+            .group(Vec::new())
+            .cors(Cors::public())
+            .add(Method::GET, "/", views::greet)
+            .add(Method::GET, "/getproblems", views::get_all_problems)
+            .add(Method::GET, "/problem/:id", views::get_problem_by_id)
+            .add(Method::GET, "/user/:name/solves", views::get_user_solves)
+            .add(Method::GET, "/submissions/:id/events", views::submission_events);
+        // Guarded by METRICS_TOKEN when one is set.
+        router.add(Method::GET, "/metrics", views::metrics);
+
+        router
+            .group(vec![Arc::new(Auth)])
+            .add(Method::POST, "/addproblem", views::add_problem);
+        // After Auth, so the limit also follows the user across addresses.
+        router
+            .group(vec![Arc::new(Auth), Arc::new(RateLimit::new(config.rate_limit_solve))])
+            .add(Method::POST, "/solve/:id", views::solve_problem);
+
+        if let Some(dir) = &config.static_dir {
+            router.serve_static(StaticFiles::new(dir.clone(), &config.static_prefix, config.static_max_age));
+        }
+
+        router
+    }
+
+    /// An empty router whose routes default to `cors`.
+    pub fn with_cors(cors: Cors) -> Router {
+        Router {
+            routes: Vec::new(),
+            middleware: Vec::new(),
+            cors: Arc::new(cors),
+            static_files: None,
+        }
+    }
+
+    /// Serves files for paths that no route claims.
+    pub fn serve_static(&mut self, files: StaticFiles) -> &mut Router {
+        self.static_files = Some(files);
+        self
+    }
+
+    /// Adds a middleware around every request.
+    pub fn wrap(&mut self, middleware: Arc<dyn Middleware>) -> &mut Router {
+        self.middleware.push(middleware);
+        self
+    }
+
+    pub fn add(&mut self, method: Method, pattern: &str, handler: Handler) -> &mut Router {
+        self.add_route(method, pattern, handler, Vec::new(), None);
+        self
+    }
+
+    /// Starts a group of routes that all run inside `middleware`.
+    pub fn group(&mut self, middleware: Vec<Arc<dyn Middleware>>) -> Group<'_> {
+        Group { router: self, middleware, cors: None }
+    }
+
+    fn add_route(
+        &mut self,
+        method: Method,
+        pattern: &str,
+        handler: Handler,
+        middleware: Vec<Arc<dyn Middleware>>,
+        cors: Option<Arc<Cors>>,
+    ) {
+        let segments = split_path(pattern)
+            .into_iter()
+            .map(|part| match part.strip_prefix(':') {
+                Some(name) => Segment::Param(name.to_string()),
+                None => Segment::Literal(part.to_string()),
+            })
+            .collect();
+
+        self.routes.push(Route { method, pattern: pattern.to_string(), segments, handler, middleware, cors });
     }
-    pub fn route(request: &Request) -> Response {
-        if let Some(problem_id) = Self::extract_problem_id(request.get_path()) {
-            return match request.get_method() {
-                Method::GET => views::get_problem_by_id(&request, problem_id),
-                Method::OPTIONS => views::handle_options(&request),
-                _ => views::not_found(&request),
-            };
+
+    pub fn route(&self, request: &mut Request) -> Response {
+        let response = middleware::run(&chain(&self.middleware), request, |request| self.resolve(request));
+
+        // Labelled by pattern rather than path, so `/problem/1` and `/problem/2` share a series.
+        let route = request.get_route().unwrap_or("unmatched");
+        let method = request.get_method().as_str();
+        metrics::HTTP_REQUESTS.inc(&[method, route, &response.get_status().to_string()]);
+        metrics::HTTP_REQUEST_DURATION.observe(&[method, route], request.received_at().elapsed().as_secs_f64());
+
+        // HEAD is answered like GET everywhere, minus the body.
+        match request.get_method() {
+            Method::HEAD => response.without_body(),
+            _ => response,
         }
+    }
+
+    /// Finds the route for `request` and runs it. Exactly one CORS policy
+    /// applies: the matched route's, or the default. It runs first, so it can
+    /// answer preflights and still decorates errors from later middleware.
+    fn resolve(&self, request: &mut Request) -> Response {
+        let path = request.get_path().clone();
+        let parts = split_path(&path);
+        let method = *request.get_method();
+        // A preflight is governed by the route it asks about.
+        let preflight = request
+            .get_header("Access-Control-Request-Method")
+            .and_then(Method::from_str)
+            .filter(|_| method == Method::OPTIONS);
+        let mut cors = &self.cors;
+        let mut allowed = Vec::new();
 
-        if let Some(problem_id) = Self::extract_solve_id(request.get_path()) {
-            return match request.get_method() {
-                Method::POST => views::solve_problem(&request, problem_id),
-                Method::OPTIONS => views::handle_options(&request),
-                _ => views::not_found(&request),
-            };
+        for route in &self.routes {
+            let Some(params) = route.matches(&parts) else { continue };
+            let route_cors = route.cors.as_ref().unwrap_or(&self.cors);
+            if route.method == method || route.method == Method::GET && method == Method::HEAD {
+                request.set_route(route.pattern.clone());
+                let mut stack: Vec<&dyn Middleware> = vec![route_cors.as_ref()];
+                stack.extend(chain(&route.middleware));
+                return middleware::run(&stack, request, |request| {
+                    // A panicking view still gets a response that the middleware can finish.
+                    panic::catch_unwind(AssertUnwindSafe(|| (route.handler)(request, &params)))
+                        .unwrap_or_else(|_| views::internal_error(request, &params))
+                });
+            }
+            if preflight == Some(route.method) {
+                cors = route_cors;
+            }
+            if !allowed.contains(&route.method) {
+                allowed.push(route.method);
+            }
         }
 
-        let handler = Self::path_to_handler(&request.get_path(), &request.get_method());
-        handler(request)
+        if allowed.is_empty() {
+            return middleware::run(&[cors.as_ref()], request, |request| {
+                let file = self.static_files.as_ref().and_then(|files| files.serve(request));
+                if file.is_some() {
+                    request.set_route("static".to_string());
+                }
+                file.unwrap_or_else(|| views::not_found(request, &Params::default()))
+            });
+        }
+
+        if allowed.contains(&Method::GET) {
+            allowed.push(Method::HEAD);
+        }
+        allowed.push(Method::OPTIONS);
+        let allow = allowed.iter().map(Method::as_str).collect::<Vec<_>>().join(", ");
+
+        let mut response = middleware::run(&[cors.as_ref()], request, |request| match method {
+            Method::OPTIONS => views::handle_options(request, &Params::default()),
+            _ => views::method_not_allowed(request, &Params::default()),
+        });
+        response.set_header("Allow", &allow);
+        response
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use crate::middleware::list;
 
-    fn path_to_handler(path: &str, method: &Method) -> fn(&Request) -> Response {
-        match (path, method) {
-            ("/" | "", &Method::GET) => views::greet,
-            ("/signup/" | "/signup", &Method::POST) => views::signup,
-            ("/login/" | "/login", &Method::POST) => views::login,
-            ("/ide/" | "/ide", &Method::POST) => views::ide,
-            ("/addproblem/" | "/addproblem", &Method::POST) => views::add_problem,
-            ("/getproblems/" | "/getproblems", &Method::GET) => views::get_all_problems,
-            (_, &Method::OPTIONS) => views::handle_options,
-            (_, _) => views::not_found,
+    fn cors() -> Cors {
+        Cors {
+            allowed_origins: list("http://localhost:3000"),
+            allowed_methods: list("GET, POST"),
+            allowed_headers: list("Content-Type"),
+            allow_credentials: false,
+            max_age: 600,
         }
     }
 
-    fn extract_problem_id(path: &str) -> Option<u64> {
-        let parts: Vec<&str> = path.split('/').collect();
-        if parts.len() > 2 && parts[1] == "problem" {
-            parts[2].parse().ok()
-        } else {
-            None
+    fn problem(_request: &Request, params: &Params) -> Response {
+        Response::text(200, format!("problem {}", params.get::<u64>("id").unwrap()))
+    }
+
+    fn solve(_request: &Request, params: &Params) -> Response {
+        Response::text(200, format!("solve {} as {}", params.raw("id").unwrap(), params.raw("lang").unwrap()))
+    }
+
+    fn router() -> Router {
+        let mut router = Router::with_cors(cors());
+        router
+            .add(Method::GET, "/problem/:id", problem)
+            .add(Method::POST, "/solve/:id/:lang", solve);
+        router
+    }
+
+    fn send(router: &Router, raw: &str) -> Response {
+        router.route(&mut Request::from_raw(raw))
+    }
+
+    fn body(response: &Response) -> String {
+        String::from_utf8_lossy(response.get_body()).into_owned()
+    }
+
+    #[test]
+    fn captures_params_and_normalizes_slashes() {
+        let router = router();
+        for target in ["/problem/42", "/problem/42/", "//problem//42"] {
+            let response = send(&router, &format!("GET {} HTTP/1.1\r\n\r\n", target));
+            assert_eq!(body(&response), "problem 42", "{}", target);
         }
+        let response = send(&router, "POST /solve/7/cpp HTTP/1.1\r\nContent-Length: 0\r\n\r\n");
+        assert_eq!(body(&response), "solve 7 as cpp");
+
+        assert_eq!(send(&router, "GET /problem HTTP/1.1\r\n\r\n").get_status(), 404);
+        assert_eq!(send(&router, "GET /problem/42/extra HTTP/1.1\r\n\r\n").get_status(), 404);
+    }
+
+    #[test]
+    fn answers_405_with_allow_for_known_paths() {
+        let router = router();
+        let response = send(&router, "DELETE /problem/1 HTTP/1.1\r\n\r\n");
+        assert_eq!(response.get_status(), 405);
+        assert_eq!(response.get_header("Allow"), Some("GET, HEAD, OPTIONS"));
+
+        let response = send(&router, "GET /solve/1/cpp HTTP/1.1\r\n\r\n");
+        assert_eq!(response.get_status(), 405);
+        assert_eq!(response.get_header("Allow"), Some("POST, OPTIONS"));
+
+        let response = send(&router, "DELETE /nowhere HTTP/1.1\r\n\r\n");
+        assert_eq!(response.get_status(), 404);
+        assert_eq!(response.get_header("Allow"), None);
+    }
+
+    #[test]
+    fn answers_options_and_head() {
+        let router = router();
+        let response = send(&router, "OPTIONS /problem/1 HTTP/1.1\r\n\r\n");
+        assert_eq!(response.get_status(), 204);
+        assert_eq!(response.get_header("Allow"), Some("GET, HEAD, OPTIONS"));
+
+        let bytes = send(&router, "HEAD /problem/1 HTTP/1.1\r\n\r\n").to_bytes();
+        let text = String::from_utf8(bytes).unwrap();
+        assert!(text.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(text.contains("Content-Length: 9\r\n"), "{}", text);
+        assert!(text.ends_with("\r\n\r\n"), "HEAD must not carry a body: {}", text);
+    }
+
+    /// Records its hooks in a shared log; answers in `before` if `answer` is set.
+    struct Record {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+        answer: bool,
     }
 
-    fn extract_solve_id(path: &str) -> Option<u64> {
-        let parts: Vec<&str> = path.split('/').collect();
-        if parts.len() > 2 && parts[1] == "solve" {
-            parts[2].parse().ok()
-        } else {
-            None
+    impl Middleware for Record {
+        fn before(&self, _request: &mut Request) -> Option<Response> {
+            self.log.lock().unwrap().push(format!("before {}", self.name));
+            self.answer.then(|| Response::text(403, self.name))
         }
+
+        fn after(&self, _request: &Request, _response: &mut Response) {
+            self.log.lock().unwrap().push(format!("after {}", self.name));
+        }
+    }
+
+    #[test]
+    fn runs_middleware_in_order_and_unwinds_in_reverse() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let record = |name, answer| Arc::new(Record { name, log: Arc::clone(&log), answer }) as Arc<dyn Middleware>;
+
+        let mut router = Router::with_cors(cors());
+        router.wrap(record("outer", false)).wrap(record("inner", false));
+        router.group(vec![record("route", false)]).add(Method::GET, "/problem/:id", problem);
+        router.group(vec![record("guard", true), record("skipped", false)]).add(Method::GET, "/secret", problem);
+
+        assert_eq!(send(&router, "GET /problem/1 HTTP/1.1\r\n\r\n").get_status(), 200);
+        assert_eq!(
+            log.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            ["before outer", "before inner", "before route", "after route", "after inner", "after outer"]
+        );
+
+        // A short circuit skips the handler and everything after it, but the
+        // middleware that already ran still see the response.
+        let response = send(&router, "GET /secret HTTP/1.1\r\n\r\n");
+        assert_eq!((response.get_status(), body(&response)), (403, "guard".to_string()));
+        assert_eq!(
+            log.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            ["before outer", "before inner", "before guard", "after guard", "after inner", "after outer"]
+        );
+
+        // Router-wide middleware wrap 404s too; route middleware don't.
+        assert_eq!(send(&router, "GET /nowhere HTTP/1.1\r\n\r\n").get_status(), 404);
+        assert_eq!(
+            log.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            ["before outer", "before inner", "after inner", "after outer"]
+        );
     }
 }
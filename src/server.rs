@@ -0,0 +1,950 @@
+use libc::syscall;
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use crate::config::{get_config, Listen};
+use crate::logging::{self, Level};
+use crate::models::codehandler;
+use crate::network::{self, Event, Limits, Request, Response, Upgrade};
+use crate::pool::{self, ThreadPool};
+use crate::routes::Router;
+use crate::websocket;
+
+const SYS_READ: i64 = 0;
+const SYS_WRITE: i64 = 1;
+const SYS_CLOSE: i64 = 3;
+const SYS_DUP: i64 = 32;
+const SYS_RT_SIGPROCMASK: i64 = 14;
+const SYS_SOCKET: i64 = 41;
+const SYS_BIND: i64 = 49;
+const SYS_LISTEN: i64 = 50;
+const SYS_SHUTDOWN: i64 = 48;
+const SYS_SETSOCKOPT: i64 = 54;
+const SYS_FCNTL: i64 = 72;
+const SYS_EPOLL_WAIT: i64 = 232;
+const SYS_EPOLL_CTL: i64 = 233;
+const SYS_ACCEPT4: i64 = 288;
+const SYS_SIGNALFD4: i64 = 289;
+const SYS_EVENTFD2: i64 = 290;
+const SYS_EPOLL_CREATE1: i64 = 291;
+
+const AF_UNIX: i32 = 1;
+const AF_INET: i32 = 2;
+const AF_INET6: i32 = 10;
+const SOCK_STREAM: i32 = 1;
+const SOCK_NONBLOCK: i32 = 0o4000;
+const SOCK_CLOEXEC: i32 = 0o2000000;
+
+const SOL_SOCKET: i32 = 1;
+const SO_REUSEADDR: i32 = 2;
+const SHUT_RDWR: i32 = 2;
+const F_GETFL: i32 = 3;
+const F_SETFL: i32 = 4;
+const O_NONBLOCK: i64 = 0o4000;
+const IPPROTO_IPV6: i32 = 41;
+const IPV6_V6ONLY: i32 = 26;
+
+const EPOLL_CLOEXEC: i32 = 0o2000000;
+const EPOLL_CTL_ADD: i32 = 1;
+const EPOLL_CTL_DEL: i32 = 2;
+const EPOLLIN: u32 = 0x001;
+const EPOLLOUT: u32 = 0x004;
+const EPOLLERR: u32 = 0x008;
+const EPOLLHUP: u32 = 0x010;
+const EPOLLRDHUP: u32 = 0x2000;
+const EPOLLET: u32 = 1 << 31;
+
+const EFD_NONBLOCK: i32 = 0o4000;
+const EFD_CLOEXEC: i32 = 0o2000000;
+
+const SIG_BLOCK: i32 = 0;
+const SIGINT: u32 = 2;
+const SIGTERM: u32 = 15;
+const SFD_NONBLOCK: i32 = 0o4000;
+const SFD_CLOEXEC: i32 = 0o2000000;
+/// Size of `struct signalfd_siginfo`; `ssi_signo` is its first field.
+const SIGINFO_SIZE: usize = 128;
+
+const EAGAIN: i32 = 11;
+const EINTR: i32 = 4;
+
+const LISTENER: u64 = 0;
+const WAKER: u64 = 1;
+const SIGNALS: u64 = 2;
+const MAX_EVENTS: usize = 256;
+const TICK_MS: i32 = 1000;
+/// How often an event stream with nothing to say sends a comment, so proxies
+/// keep it open and a vanished client is noticed.
+const HEARTBEAT: Duration = Duration::from_secs(15);
+/// Room in a connection's input buffer beyond the largest request we accept,
+/// for chunk framing and the start of a pipelined request.
+const INPUT_SLACK: usize = 64 * 1024;
+
+#[repr(C)]
+struct SockAddrIn {
+    sin_family: u16,
+    sin_port: u16,
+    sin_addr: u32,
+    sin_zero: [u8; 8],
+}
+
+#[repr(C)]
+struct SockAddrIn6 {
+    sin6_family: u16,
+    sin6_port: u16,
+    sin6_flowinfo: u32,
+    sin6_addr: [u8; 16],
+    sin6_scope_id: u32,
+}
+
+#[repr(C)]
+struct SockAddrUn {
+    sun_family: u16,
+    sun_path: [u8; 108],
+}
+
+/// Large enough for any address `accept4` hands back (`struct sockaddr_storage`).
+#[repr(C, align(8))]
+struct SockAddrStorage {
+    family: u16,
+    data: [u8; 126],
+}
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct EpollEvent {
+    events: u32,
+    data: u64,
+}
+
+fn htons(port: u16) -> u16 {
+    port.to_be()
+}
+
+/// The IP address in a socket address filled in by `accept4`. IPv4 clients of a
+/// dual-stack socket arrive v4-mapped and are reported as plain IPv4.
+fn peer_ip(addr: &SockAddrStorage) -> Option<IpAddr> {
+    match i32::from(addr.family) {
+        // sin_port comes first, then the address.
+        AF_INET => {
+            let octets: [u8; 4] = addr.data[2..6].try_into().ok()?;
+            Some(IpAddr::V4(Ipv4Addr::from(octets)))
+        },
+        // sin6_port and sin6_flowinfo come before the address.
+        AF_INET6 => {
+            let octets: [u8; 16] = addr.data[6..22].try_into().ok()?;
+            Some(IpAddr::V6(Ipv6Addr::from(octets)).to_canonical())
+        },
+        _ => None,
+    }
+}
+
+fn errno() -> i32 {
+    io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
+fn close(fd: i32) {
+    unsafe {
+        syscall(SYS_CLOSE, fd);
+    }
+}
+
+fn set_option(fd: i32, level: i32, name: i32, value: i32) {
+    let res = unsafe {
+        syscall(SYS_SETSOCKOPT, fd, level, name, &value as *const i32, mem::size_of::<i32>() as u32)
+    };
+    if res < 0 {
+        panic!("setsockopt syscall failed: {}", io::Error::last_os_error());
+    }
+}
+
+/// Blocks SIGINT/SIGTERM and returns a signalfd that reports them instead.
+/// Must run before any thread is spawned so every thread inherits the mask.
+fn shutdown_signals() -> i32 {
+    let mask: u64 = (1 << (SIGINT - 1)) | (1 << (SIGTERM - 1));
+    unsafe {
+        let res = syscall(SYS_RT_SIGPROCMASK, SIG_BLOCK, &mask as *const u64, std::ptr::null_mut::<u64>(), mem::size_of::<u64>());
+        if res < 0 {
+            panic!("rt_sigprocmask syscall failed: {}", io::Error::last_os_error());
+        }
+
+        let fd = syscall(SYS_SIGNALFD4, -1, &mask as *const u64, mem::size_of::<u64>(), SFD_NONBLOCK | SFD_CLOEXEC) as i32;
+        if fd < 0 {
+            panic!("signalfd4 syscall failed: {}", io::Error::last_os_error());
+        }
+        fd
+    }
+}
+
+fn bind_socket<T>(family: i32, addr: &T) -> i32 {
+    unsafe {
+        let fd = syscall(SYS_SOCKET, family, SOCK_STREAM | SOCK_NONBLOCK | SOCK_CLOEXEC, 0) as i32;
+        if fd < 0 {
+            panic!("socket syscall failed: {}", io::Error::last_os_error());
+        }
+
+        if family != AF_UNIX {
+            // Lets a restarted server rebind while old connections sit in TIME_WAIT.
+            set_option(fd, SOL_SOCKET, SO_REUSEADDR, 1);
+        }
+        if family == AF_INET6 {
+            // Accept IPv4 clients on the same socket as v4-mapped addresses.
+            set_option(fd, IPPROTO_IPV6, IPV6_V6ONLY, 0);
+        }
+
+        let res = syscall(SYS_BIND, fd, addr as *const T, mem::size_of::<T>() as u32);
+        if res < 0 {
+            panic!("bind syscall failed: {}", io::Error::last_os_error());
+        }
+        fd
+    }
+}
+
+fn bind_tcp(addr: &SocketAddr) -> i32 {
+    match addr {
+        SocketAddr::V4(v4) => bind_socket(AF_INET, &SockAddrIn {
+            sin_family: AF_INET as u16,
+            sin_port: htons(v4.port()),
+            sin_addr: u32::from_ne_bytes(v4.ip().octets()),
+            sin_zero: [0; 8],
+        }),
+        SocketAddr::V6(v6) => bind_socket(AF_INET6, &SockAddrIn6 {
+            sin6_family: AF_INET6 as u16,
+            sin6_port: htons(v6.port()),
+            sin6_flowinfo: 0,
+            sin6_addr: v6.ip().octets(),
+            sin6_scope_id: v6.scope_id(),
+        }),
+    }
+}
+
+fn bind_unix(path: &Path) -> i32 {
+    let bytes = path.as_os_str().as_encoded_bytes();
+    let mut sun_path = [0u8; 108];
+    if bytes.len() >= sun_path.len() {
+        panic!("Unix socket path is too long: {}", path.display());
+    }
+    sun_path[..bytes.len()].copy_from_slice(bytes);
+
+    // A socket file left behind by a previous run would make bind fail.
+    let _ = std::fs::remove_file(path);
+    bind_socket(AF_UNIX, &SockAddrUn {
+        sun_family: AF_UNIX as u16,
+        sun_path,
+    })
+}
+
+fn describe(peer: Option<IpAddr>) -> String {
+    peer.map_or_else(|| "unix socket".to_string(), |ip| ip.to_string())
+}
+
+/// Who sent `request`: when we trust the proxies in front of us, the address
+/// the outermost of them saw, otherwise the peer itself.
+fn client_ip(request: &Request, peer: Option<IpAddr>) -> Option<IpAddr> {
+    let config = get_config();
+    if !config.trust_proxy {
+        return peer;
+    }
+    let forwarded = request.get_headers().get_all("X-Forwarded-For").join(",");
+    forwarded_client(&forwarded, config.proxy_hops).or(peer)
+}
+
+/// Each proxy appends the address it got the request from, so the entry
+/// `hops` from the right was written by our outermost proxy. Anything to its
+/// left came from the client and could say anything.
+fn forwarded_client(forwarded: &str, hops: usize) -> Option<IpAddr> {
+    let entries: Vec<&str> = forwarded.split(',').map(str::trim).filter(|entry| !entry.is_empty()).collect();
+    let index = entries.len().checked_sub(hops.max(1))?;
+    entries[index].parse().ok()
+}
+
+/// A client connection taken out of the event loop after a protocol upgrade,
+/// with ordinary blocking reads and writes. Closed when dropped.
+pub struct Socket {
+    fd: i32,
+    /// Bytes the client sent after the upgrade request, before we let go.
+    pending: Vec<u8>,
+}
+
+impl Socket {
+    /// A second handle on the same connection, e.g. to write from another thread.
+    pub fn try_clone(&self) -> io::Result<Socket> {
+        let fd = unsafe { syscall(SYS_DUP, self.fd) } as i32;
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Socket { fd, pending: Vec::new() })
+    }
+
+    /// Ends the connection for every handle on it, waking any blocked reader.
+    pub fn shutdown(&self) {
+        unsafe {
+            syscall(SYS_SHUTDOWN, self.fd, SHUT_RDWR);
+        }
+    }
+}
+
+impl io::Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.pending.is_empty() {
+            let n = buf.len().min(self.pending.len());
+            buf[..n].copy_from_slice(&self.pending[..n]);
+            self.pending.drain(..n);
+            return Ok(n);
+        }
+        loop {
+            let n = unsafe { syscall(SYS_READ, self.fd, buf.as_mut_ptr(), buf.len()) };
+            if n >= 0 {
+                return Ok(n as usize);
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+}
+
+impl io::Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            let n = unsafe { syscall(SYS_WRITE, self.fd, buf.as_ptr(), buf.len()) };
+            if n >= 0 {
+                return Ok(n as usize);
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        close(self.fd);
+    }
+}
+
+/// Response bytes handed back from a worker to the event loop. A streamed
+/// response arrives as several of these, the last one marked `done`.
+struct Completion {
+    token: u64,
+    bytes: Vec<u8>,
+    keep_alive: bool,
+    done: bool,
+    /// Takes over the connection once `bytes`, a 101 response, are written.
+    upgrade: Option<Upgrade>,
+    /// Comes with the head of an event stream; see `Connection::stream`.
+    stream: Option<Arc<()>>,
+}
+
+/// Queues `completion` for the event loop and wakes it up.
+fn complete(sender: &Sender<Completion>, waker: i32, completion: Completion) {
+    let _ = sender.send(completion);
+    let one = 1u64;
+    unsafe {
+        syscall(SYS_WRITE, waker, &one as *const u64, mem::size_of::<u64>());
+    }
+}
+
+static OPEN_STREAMS: AtomicUsize = AtomicUsize::new(0);
+
+/// An event stream's place among the open ones, given up when dropped.
+struct StreamSlot;
+
+impl StreamSlot {
+    /// `None` when `MAX_STREAMS` are already open.
+    fn reserve() -> Option<StreamSlot> {
+        let limit = get_config().max_streams;
+        OPEN_STREAMS
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| (open < limit).then_some(open + 1))
+            .ok()
+            .map(|_| StreamSlot)
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        OPEN_STREAMS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Passes an event stream on to the event loop until it ends or its connection
+/// is gone. Runs on a thread of its own, since a stream can stay open far longer
+/// than a worker should be held.
+fn forward_events(events: Receiver<Event>, token: u64, sender: Sender<Completion>, waker: i32, connection: Weak<()>, _slot: StreamSlot) {
+    while connection.strong_count() > 0 {
+        let bytes = match events.recv_timeout(HEARTBEAT) {
+            Ok(event) => event.to_bytes(),
+            Err(RecvTimeoutError::Timeout) => b": keep-alive\n\n".to_vec(),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        complete(&sender, waker, Completion { token, bytes, keep_alive: false, done: false, upgrade: None, stream: None });
+    }
+    complete(&sender, waker, Completion { token, bytes: Vec::new(), keep_alive: false, done: true, upgrade: None, stream: None });
+}
+
+struct Connection {
+    fd: i32,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    written: usize,
+    /// A request from this connection is being handled by a worker. Pipelined
+    /// requests wait in `input` so responses go out in order.
+    in_flight: bool,
+    /// `input` is full, so we've stopped reading and left the rest to TCP flow
+    /// control. Reading resumes once a request is taken out of it.
+    paused: bool,
+    /// Close once `output` has been flushed.
+    closing: bool,
+    /// The peer shut down its side; serve what is buffered, then close.
+    eof: bool,
+    last_active: Instant,
+    /// When we started waiting on the rest of a partly received request, and
+    /// when its head was complete. Both are cleared once it's dispatched.
+    request_started: Option<Instant>,
+    head_received: Option<Instant>,
+    /// The last time the client accepted any of `output`.
+    last_write: Instant,
+    /// `None` for Unix socket clients.
+    peer: Option<IpAddr>,
+    /// Set once a 101 response is queued; the connection is handed over when it's sent.
+    upgrade: Option<Upgrade>,
+    /// Held while an event stream feeds the connection. Its thread only has a
+    /// weak reference, so it stops once the connection is dropped.
+    stream: Option<Arc<()>>,
+}
+
+impl Connection {
+    fn idle(&self) -> bool {
+        !self.in_flight && self.output.is_empty()
+    }
+}
+
+/// Single-threaded epoll reactor. All socket I/O is non-blocking and happens here;
+/// routing (and with it the judge) runs on the worker pool, which posts finished
+/// responses back through a channel and an eventfd.
+pub struct Server {
+    epfd: i32,
+    listener: i32,
+    waker: i32,
+    signals: i32,
+    /// Set once a shutdown signal arrives; the loop exits when drained or past it.
+    deadline: Option<Instant>,
+    pool: &'static ThreadPool,
+    router: Arc<Router>,
+    limits: Limits,
+    /// How much of a connection's input we buffer, see `INPUT_SLACK`.
+    max_input: usize,
+    connections: HashMap<u64, Connection>,
+    next_token: u64,
+    sender: Sender<Completion>,
+    completions: Receiver<Completion>,
+}
+
+impl Server {
+    pub fn bind(router: Router) -> Server {
+        let config = get_config();
+        let signals = shutdown_signals();
+        // Only now, so the workers inherit the blocked signal mask.
+        pool::init_pool(config.workers, config.queue_limit);
+        let listener = match &config.listen {
+            Listen::Tcp(addr) => bind_tcp(addr),
+            Listen::Unix(path) => bind_unix(path),
+        };
+        let rate_limited = [config.rate_limit_login, config.rate_limit_ide, config.rate_limit_solve]
+            .iter()
+            .any(|rate| !rate.unlimited());
+        if matches!(config.listen, Listen::Unix(_)) && !config.trust_proxy && rate_limited {
+            logging::log(Level::Warn, "Unix socket clients have no address without TRUST_PROXY, so only per-user rate limits apply");
+        }
+
+        unsafe {
+            let res = syscall(SYS_LISTEN, listener, config.backlog);
+            if res < 0 {
+                panic!("listen syscall failed: {}", io::Error::last_os_error());
+            }
+
+            let epfd = syscall(SYS_EPOLL_CREATE1, EPOLL_CLOEXEC) as i32;
+            if epfd < 0 {
+                panic!("epoll_create1 syscall failed");
+            }
+
+            let waker = syscall(SYS_EVENTFD2, 0, EFD_NONBLOCK | EFD_CLOEXEC) as i32;
+            if waker < 0 {
+                panic!("eventfd2 syscall failed");
+            }
+
+            let (sender, completions) = mpsc::channel();
+            let server = Server {
+                epfd,
+                listener,
+                waker,
+                signals,
+                deadline: None,
+                pool: pool::get_pool(),
+                router: Arc::new(router),
+                limits: Limits {
+                    max_body_size: config.max_body_size,
+                    max_header_size: config.max_header_size,
+                    max_header_count: config.max_header_count,
+                },
+                max_input: config.max_header_size + config.max_body_size + INPUT_SLACK,
+                connections: HashMap::new(),
+                next_token: SIGNALS + 1,
+                sender,
+                completions,
+            };
+            server.register(EPOLL_CTL_ADD, listener, LISTENER, EPOLLIN);
+            server.register(EPOLL_CTL_ADD, waker, WAKER, EPOLLIN);
+            server.register(EPOLL_CTL_ADD, signals, SIGNALS, EPOLLIN);
+            server
+        }
+    }
+
+    /// Serves until SIGINT/SIGTERM, then drains in-flight requests for up to
+    /// `SHUTDOWN_TIMEOUT` seconds before returning.
+    pub fn run(&mut self) {
+        let mut events = [EpollEvent { events: 0, data: 0 }; MAX_EVENTS];
+
+        loop {
+            let n = unsafe {
+                syscall(SYS_EPOLL_WAIT, self.epfd, events.as_mut_ptr(), MAX_EVENTS as i32, TICK_MS)
+            };
+            if n < 0 {
+                if errno() != EINTR {
+                    logging::error(&format!("epoll_wait failed: {}", io::Error::last_os_error()));
+                }
+                continue;
+            }
+
+            for event in &events[..n as usize] {
+                let (token, flags) = (event.data, event.events);
+                match token {
+                    LISTENER => self.accept(),
+                    WAKER => self.collect_completions(),
+                    SIGNALS => self.begin_shutdown(),
+                    _ => self.service(token, flags),
+                }
+            }
+
+            self.enforce_timeouts();
+
+            if let Some(deadline) = self.deadline {
+                // Background jobs, such as `?async` judging, and upgraded
+                // sessions have no connection in the loop to wait on.
+                if self.connections.is_empty() && self.pool.pending() == 0 && websocket::open_sessions() == 0 {
+                    logging::info("All requests drained");
+                    break;
+                }
+                if Instant::now() >= deadline {
+                    logging::log(Level::Warn, &format!(
+                        "Shutdown deadline reached, abandoning {} connection(s), {} job(s) and {} session(s)",
+                        self.connections.len(),
+                        self.pool.pending(),
+                        websocket::open_sessions()
+                    ));
+                    websocket::abort_all();
+                    break;
+                }
+            }
+        }
+
+        codehandler::cleanup_workspaces();
+        if let Listen::Unix(path) = &get_config().listen {
+            let _ = std::fs::remove_file(path);
+        }
+        logging::info("Server stopped");
+    }
+
+    fn begin_shutdown(&mut self) {
+        let mut info = [0u8; SIGINFO_SIZE];
+        let n = unsafe { syscall(SYS_READ, self.signals, info.as_mut_ptr(), SIGINFO_SIZE) };
+        if n < SIGINFO_SIZE as i64 || self.deadline.is_some() {
+            return;
+        }
+        let signo = u32::from_ne_bytes([info[0], info[1], info[2], info[3]]);
+
+        logging::info(&format!(
+            "Received {}, no longer accepting connections; draining {} connection(s)",
+            if signo == SIGINT { "SIGINT" } else { "SIGTERM" },
+            self.connections.len()
+        ));
+
+        self.register(EPOLL_CTL_DEL, self.listener, LISTENER, 0);
+        close(self.listener);
+        self.deadline = Some(Instant::now() + Duration::from_secs(get_config().shutdown_timeout));
+        websocket::close_all();
+
+        let tokens: Vec<u64> = self.connections.keys().copied().collect();
+        for token in tokens {
+            let Some(conn) = self.connections.get_mut(&token) else { continue };
+            conn.closing = true;
+            if conn.idle() {
+                self.close(token);
+            }
+        }
+    }
+
+    fn register(&self, op: i32, fd: i32, token: u64, events: u32) {
+        let mut event = EpollEvent { events, data: token };
+        unsafe {
+            syscall(SYS_EPOLL_CTL, self.epfd, op, fd, &mut event as *mut EpollEvent);
+        }
+    }
+
+    fn accept(&mut self) {
+        loop {
+            let mut addr = SockAddrStorage { family: 0, data: [0; 126] };
+            let mut len = mem::size_of::<SockAddrStorage>() as u32;
+            let fd = unsafe {
+                syscall(
+                    SYS_ACCEPT4,
+                    self.listener,
+                    &mut addr as *mut SockAddrStorage,
+                    &mut len as *mut u32,
+                    SOCK_NONBLOCK | SOCK_CLOEXEC,
+                )
+            } as i32;
+            if fd < 0 {
+                if errno() != EAGAIN {
+                    logging::error(&format!("accept failed: {}", io::Error::last_os_error()));
+                }
+                return;
+            }
+
+            // Edge-triggered: reads and writes always run until EAGAIN, so the
+            // interest set never has to change over the connection's lifetime.
+            let token = self.next_token;
+            self.next_token += 1;
+            self.register(EPOLL_CTL_ADD, fd, token, EPOLLIN | EPOLLOUT | EPOLLRDHUP | EPOLLET);
+            self.connections.insert(token, Connection {
+                fd,
+                input: Vec::new(),
+                output: Vec::new(),
+                written: 0,
+                in_flight: false,
+                paused: false,
+                closing: false,
+                eof: false,
+                last_active: Instant::now(),
+                request_started: None,
+                head_received: None,
+                last_write: Instant::now(),
+                peer: peer_ip(&addr),
+                upgrade: None,
+                stream: None,
+            });
+        }
+    }
+
+    fn service(&mut self, token: u64, flags: u32) {
+        if flags & (EPOLLIN | EPOLLRDHUP | EPOLLHUP | EPOLLERR) != 0 {
+            self.read(token);
+        }
+        if flags & EPOLLOUT != 0 {
+            self.flush(token);
+        }
+    }
+
+    fn read(&mut self, token: u64) {
+        let Some(conn) = self.connections.get_mut(&token) else { return };
+        let mut chunk = [0u8; 8192];
+        conn.paused = false;
+
+        loop {
+            // Stopping short of EAGAIN means no new edge: `dispatch` calls us
+            // again once there is room.
+            let room = self.max_input.saturating_sub(conn.input.len()).min(chunk.len());
+            if room == 0 {
+                conn.paused = true;
+                break;
+            }
+            let n = unsafe { syscall(SYS_READ, conn.fd, chunk.as_mut_ptr(), room) };
+            if n > 0 {
+                conn.input.extend_from_slice(&chunk[..n as usize]);
+                conn.last_active = Instant::now();
+                continue;
+            }
+            let err = errno();
+            if n < 0 && err == EAGAIN {
+                break;
+            }
+            if n < 0 && err == EINTR {
+                continue;
+            }
+            conn.eof = true;
+            break;
+        }
+
+        // An event stream's client has nothing to send; EOF means it left.
+        if conn.eof && conn.stream.is_some() {
+            self.close(token);
+            return;
+        }
+        self.dispatch(token);
+    }
+
+    /// Hands the next complete buffered request to the worker pool.
+    fn dispatch(&mut self, token: u64) {
+        let Some(conn) = self.connections.get_mut(&token) else { return };
+        if !conn.idle() {
+            return;
+        }
+
+        let mut request = match Request::parse(&conn.input, &self.limits) {
+            Ok(Some((request, used))) => {
+                conn.input.drain(..used);
+                conn.request_started = None;
+                conn.head_received = None;
+                request
+            },
+            // Only chunk framing can take a request within the limits past
+            // the buffer; there's no room left to finish it.
+            Ok(None) if conn.paused => {
+                logging::log(Level::Warn, &format!("Request from {} overflowed the input buffer", describe(conn.peer)));
+                let mut response = Response::error(413, "Request too large");
+                response.set_header("Connection", "close");
+                self.respond(token, response.to_bytes(), false, true);
+                return;
+            },
+            Ok(None) => {
+                if conn.eof {
+                    self.close(token);
+                } else if !conn.input.is_empty() {
+                    // Only time spent waiting on the client counts against it.
+                    let now = Instant::now();
+                    conn.request_started.get_or_insert(now);
+                    if conn.head_received.is_none() && network::head_received(&conn.input) {
+                        conn.head_received = Some(now);
+                    }
+                }
+                return;
+            },
+            Err(e) => {
+                logging::log(Level::Warn, &format!("Rejected request from {}: {}", describe(conn.peer), e));
+                let mut response = Response::error(e.status(), e.message());
+                response.set_header("Connection", "close");
+                self.respond(token, response.to_bytes(), false, true);
+                return;
+            },
+        };
+
+        request.set_client_ip(client_ip(&request, conn.peer));
+        conn.in_flight = true;
+        let resume = conn.paused;
+        let sender = self.sender.clone();
+        let waker = self.waker;
+        let router = Arc::clone(&self.router);
+        let job = move || {
+            let mut request = request;
+            // A panicking view must still answer and release the socket.
+            let mut response = panic::catch_unwind(AssertUnwindSafe(|| router.route(&mut request)))
+                .unwrap_or_else(|_| Response::error(500, "Internal server error"));
+            // Every open stream holds a thread, so there may only be so many.
+            let slot = if response.is_stream() { StreamSlot::reserve() } else { None };
+            if response.is_stream() && slot.is_none() {
+                logging::log(Level::Warn, "Too many open event streams, answering 503");
+                response = Response::error(503, "Too many open event streams, try again later");
+            }
+            // An event stream ends when the connection does.
+            let keep_alive = request.keep_alive() && !response.is_stream();
+            let upgrade = response.take_upgrade();
+            if upgrade.is_none() {
+                response.set_header("Connection", if keep_alive { "keep-alive" } else { "close" });
+            }
+
+            let bytes = response.to_bytes();
+            let stream = response.take_stream().zip(slot);
+            let connection = stream.as_ref().map(|_| Arc::new(()));
+            complete(&sender, waker, Completion { token, bytes, keep_alive, done: stream.is_none(), upgrade, stream: connection.clone() });
+            if let (Some((events, slot)), Some(connection)) = (stream, connection) {
+                let connection = Arc::downgrade(&connection);
+                std::thread::spawn(move || forward_events(events, token, sender, waker, connection, slot));
+            }
+        };
+
+        if self.pool.execute(job).is_err() {
+            logging::log(Level::Warn, "Worker queue full, answering 503");
+            let mut response = Response::error(503, "Server is busy, try again later");
+            response.set_header("Connection", "close");
+            self.respond(token, response.to_bytes(), false, true);
+            return;
+        }
+        if resume {
+            self.read(token);
+        }
+    }
+
+    fn collect_completions(&mut self) {
+        let mut counter = 0u64;
+        unsafe {
+            syscall(SYS_READ, self.waker, &mut counter as *mut u64, mem::size_of::<u64>());
+        }
+
+        while let Ok(completion) = self.completions.try_recv() {
+            if let Some(conn) = self.connections.get_mut(&completion.token) {
+                conn.upgrade = completion.upgrade;
+                if completion.stream.is_some() {
+                    conn.stream = completion.stream;
+                }
+            }
+            self.respond(completion.token, completion.bytes, completion.keep_alive, completion.done);
+        }
+    }
+
+    /// Queues bytes of a response for writing. Until `done`, more are on the way
+    /// and the connection stays busy.
+    fn respond(&mut self, token: u64, bytes: Vec<u8>, keep_alive: bool, done: bool) {
+        let Some(conn) = self.connections.get_mut(&token) else { return };
+        if done {
+            conn.in_flight = false;
+            conn.closing |= !keep_alive;
+        }
+        if conn.output.is_empty() {
+            conn.last_write = Instant::now();
+        }
+        conn.output.extend_from_slice(&bytes);
+        self.flush(token);
+    }
+
+    fn flush(&mut self, token: u64) {
+        let Some(conn) = self.connections.get_mut(&token) else { return };
+
+        while conn.written < conn.output.len() {
+            let remaining = &conn.output[conn.written..];
+            let n = unsafe { syscall(SYS_WRITE, conn.fd, remaining.as_ptr(), remaining.len()) };
+            if n > 0 {
+                conn.written += n as usize;
+                conn.last_active = Instant::now();
+                conn.last_write = conn.last_active;
+                continue;
+            }
+            let err = errno();
+            if n < 0 && err == EINTR {
+                continue;
+            }
+            // On EAGAIN the next EPOLLOUT edge resumes the write.
+            if n >= 0 || err != EAGAIN {
+                self.close(token);
+            }
+            return;
+        }
+
+        conn.output.clear();
+        conn.written = 0;
+        if conn.upgrade.is_some() {
+            self.hand_over(token);
+            return;
+        }
+        if conn.closing && !conn.in_flight {
+            self.close(token);
+            return;
+        }
+
+        self.dispatch(token);
+    }
+
+    /// Takes an upgraded connection out of the event loop and runs its new
+    /// protocol on a thread of its own, with a blocking socket.
+    fn hand_over(&mut self, token: u64) {
+        let Some(mut conn) = self.connections.remove(&token) else { return };
+        let Some(upgrade) = conn.upgrade.take() else { return };
+        self.register(EPOLL_CTL_DEL, conn.fd, token, 0);
+
+        let socket = Socket { fd: conn.fd, pending: mem::take(&mut conn.input) };
+        let flags = unsafe { syscall(SYS_FCNTL, socket.fd, F_GETFL) };
+        if flags < 0 || unsafe { syscall(SYS_FCNTL, socket.fd, F_SETFL, flags & !O_NONBLOCK) } < 0 {
+            logging::error(&format!("Failed to hand over connection: {}", io::Error::last_os_error()));
+            return;
+        }
+        std::thread::spawn(move || upgrade.run(socket));
+    }
+
+    fn close(&mut self, token: u64) {
+        if let Some(conn) = self.connections.remove(&token) {
+            self.register(EPOLL_CTL_DEL, conn.fd, token, 0);
+            close(conn.fd);
+        }
+    }
+
+    /// Sweeps connections that are taking too long: a request trickling in
+    /// slower than the header or body timeout gets a 408, a client that stops
+    /// reading its response is dropped, and idle keep-alive connections close.
+    fn enforce_timeouts(&mut self) {
+        let config = get_config();
+        let header_timeout = Duration::from_secs(config.header_timeout);
+        let body_timeout = Duration::from_secs(config.body_timeout);
+        let write_timeout = Duration::from_secs(config.write_timeout);
+        let keep_alive_timeout = Duration::from_secs(config.keep_alive_timeout);
+
+        let mut slow_requests = Vec::new();
+        let mut expired = Vec::new();
+        for (&token, conn) in &self.connections {
+            if !conn.output.is_empty() {
+                if conn.last_write.elapsed() > write_timeout {
+                    logging::log(Level::Warn, &format!("Write to {} timed out", describe(conn.peer)));
+                    expired.push(token);
+                }
+            } else if conn.in_flight {
+                continue;
+            } else if let Some(started) = conn.request_started {
+                let late = match conn.head_received {
+                    Some(head) => head.elapsed() > body_timeout,
+                    None => started.elapsed() > header_timeout,
+                };
+                if late {
+                    let part = if conn.head_received.is_some() { "body" } else { "headers" };
+                    logging::log(Level::Warn, &format!("Timed out reading request {} from {}", part, describe(conn.peer)));
+                    slow_requests.push(token);
+                }
+            } else if conn.last_active.elapsed() > keep_alive_timeout {
+                expired.push(token);
+            }
+        }
+
+        for token in expired {
+            self.close(token);
+        }
+        for token in slow_requests {
+            if let Some(conn) = self.connections.get_mut(&token) {
+                conn.input.clear();
+                conn.request_started = None;
+                conn.head_received = None;
+            }
+            let mut response = Response::error(408, "Request timed out");
+            response.set_header("Connection", "close");
+            self.respond(token, response.to_bytes(), false, true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_the_client_from_the_trusted_end_of_x_forwarded_for() {
+        let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+        // Whatever the client put first, our proxy appended the real address.
+        assert_eq!(forwarded_client("6.6.6.6, 203.0.113.7", 1), ip("203.0.113.7"));
+        assert_eq!(forwarded_client("6.6.6.6, 203.0.113.7, 10.0.0.2", 2), ip("203.0.113.7"));
+        assert_eq!(forwarded_client("2001:db8::1", 1), ip("2001:db8::1"));
+        assert_eq!(forwarded_client("203.0.113.7", 2), None);
+        assert_eq!(forwarded_client("", 1), None);
+        assert_eq!(forwarded_client("6.6.6.6, garbage", 1), None);
+    }
+}
@@ -0,0 +1,298 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::caching;
+use crate::network::{Method, Request, Response};
+
+/// Serves a directory, typically the frontend bundle, under a URL prefix.
+/// Page loads (`Accept: text/html`) of paths without a file extension that
+/// match nothing get `index.html`, so a single-page app can do its own
+/// client-side routing; API clients still get their 404.
+pub struct StaticFiles {
+    root: PathBuf,
+    prefix: String,
+    max_age: u64,
+}
+
+fn mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "application/javascript",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "zip" => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+/// The byte range asked for by a `Range` header, as an inclusive
+/// `(first, last)` pair. `Ok(None)` means serve the whole file: no range, one we
+/// don't understand, or several at once. `Err` means nothing in the file matches.
+fn parse_range(header: &str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else { return Ok(None) };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else { return Ok(None) };
+
+    match (first.parse::<u64>(), last.parse::<u64>()) {
+        // The last `n` bytes.
+        (Err(_), Ok(suffix)) if first.is_empty() => {
+            if suffix == 0 || len == 0 {
+                return Err(());
+            }
+            Ok(Some((len.saturating_sub(suffix), len - 1)))
+        },
+        (Ok(first), Err(_)) if last.is_empty() => {
+            if first >= len {
+                return Err(());
+            }
+            Ok(Some((first, len - 1)))
+        },
+        (Ok(first), Ok(last)) if first <= last => {
+            if first >= len {
+                return Err(());
+            }
+            Ok(Some((first, last.min(len - 1))))
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Whether an `Accept` value names `text/html` itself, as browsers loading a
+/// page do. `*/*` doesn't count: every API client sends that.
+fn accepts_html(accept: &str) -> bool {
+    accept.split(',').any(|item| {
+        let mut parts = item.split(';');
+        let html = parts.next().unwrap_or("").trim().eq_ignore_ascii_case("text/html");
+        let quality = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        html && quality > 0.0
+    })
+}
+
+/// `len` bytes of the file at `path`, starting `offset` bytes in.
+fn read_part(path: &Path, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut body = Vec::with_capacity(len as usize);
+    file.take(len).read_to_end(&mut body)?;
+    Ok(body)
+}
+
+/// Whether an `If-Range` validator still describes the file, so the range applies.
+fn if_range_matches(value: &str, etag: &str, last_modified: u64) -> bool {
+    let value = value.trim();
+    if value.starts_with('"') || value.starts_with("W/") {
+        // If-Range only ever uses strong comparison.
+        value == etag
+    } else {
+        caching::parse_http_date(value) == Some(last_modified)
+    }
+}
+
+impl StaticFiles {
+    pub fn new(root: PathBuf, prefix: &str, max_age: u64) -> StaticFiles {
+        let root = root
+            .canonicalize()
+            .unwrap_or_else(|e| panic!("Static directory {} is not usable: {}", root.display(), e));
+        StaticFiles {
+            root,
+            prefix: prefix.trim_end_matches('/').to_string(),
+            max_age,
+        }
+    }
+
+    /// The part of `path` under our prefix, or `None` if it's outside it.
+    fn relative<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let rest = path.strip_prefix(&self.prefix)?;
+        if rest.is_empty() || rest.starts_with('/') {
+            Some(rest.trim_start_matches('/'))
+        } else {
+            None
+        }
+    }
+
+    /// Maps a relative URL path to a file inside the root, refusing anything a
+    /// symlink leads outside of it.
+    fn find(&self, relative: &str) -> Option<PathBuf> {
+        let mut path = self.root.clone();
+        path.extend(relative.split('/').filter(|s| !s.is_empty()));
+
+        if path.is_dir() {
+            path.push("index.html");
+        }
+        let path = path.canonicalize().ok()?;
+        (path.starts_with(&self.root) && path.is_file()).then_some(path)
+    }
+
+    /// Answers `request` from the directory, or `None` if it isn't ours to answer.
+    pub fn serve(&self, request: &Request) -> Option<Response> {
+        if !matches!(request.get_method(), Method::GET | Method::HEAD) {
+            return None;
+        }
+        let relative = self.relative(request.get_path())?;
+        // Dot segments and hidden files such as `.env` are never served.
+        if relative.split('/').any(|s| s.starts_with('.') || s.contains(['\\', '\0'])) {
+            return None;
+        }
+
+        let has_extension = relative.rsplit('/').next().unwrap_or("").contains('.');
+        let wants_page = request.get_header("Accept").is_some_and(accepts_html);
+        let path = match self.find(relative) {
+            Some(path) => path,
+            None if !has_extension && wants_page => self.find("index.html")?,
+            None => return None,
+        };
+
+        // Only the part of the file that gets sent is read, and nothing for
+        // HEAD, a 304 or a 416.
+        let (len, modified) = match fs::metadata(&path).and_then(|meta| Ok((meta.len(), meta.modified()?))) {
+            Ok(file) => file,
+            Err(e) => return Some(Response::internal_error(format!("Failed to read {}: {}", relative, e))),
+        };
+        let modified = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let etag = format!("\"{:x}-{:x}\"", modified, len);
+        let mime = mime_type(&path);
+
+        // The HTML entry point must be revalidated so new deploys are picked up;
+        // everything else can be cached for a while.
+        let cache_control = if mime.starts_with("text/html") {
+            "no-cache".to_string()
+        } else {
+            format!("public, max-age={}", self.max_age)
+        };
+
+        let response = Response::bytes(200, mime, Vec::new())
+            .with_header("Accept-Ranges", "bytes")
+            .with_header("Cache-Control", &cache_control);
+        let mut response = caching::conditional_with(request, response, etag.clone(), Some(modified));
+        if response.get_status() != 200 {
+            return Some(response);
+        }
+
+        let range = match request.get_header("Range") {
+            Some(_) if request.get_header("If-Range").is_some_and(|v| !if_range_matches(v, &etag, modified)) => Ok(None),
+            Some(range) => parse_range(range, len),
+            None => Ok(None),
+        };
+
+        let (offset, part_len) = match range {
+            Ok(None) => (0, len),
+            Ok(Some((first, last))) => {
+                response.set_status(206);
+                response.set_header("Content-Range", &format!("bytes {}-{}/{}", first, last, len));
+                (first, last - first + 1)
+            },
+            Err(()) => {
+                let response = Response::empty(416)
+                    .with_header("Content-Range", &format!("bytes */{}", len))
+                    .with_header("Accept-Ranges", "bytes");
+                return Some(response);
+            },
+        };
+
+        if *request.get_method() == Method::HEAD {
+            return Some(response.with_head_length(part_len as usize));
+        }
+        match read_part(&path, offset, part_len) {
+            Ok(body) => response.set_body(body),
+            Err(e) => return Some(Response::internal_error(format!("Failed to read {}: {}", relative, e))),
+        }
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_byte_ranges() {
+        assert_eq!(parse_range("bytes=0-4", 10), Ok(Some((0, 4))));
+        assert_eq!(parse_range("bytes=5-", 10), Ok(Some((5, 9))));
+        assert_eq!(parse_range("bytes=-3", 10), Ok(Some((7, 9))));
+        assert_eq!(parse_range("bytes=-30", 10), Ok(Some((0, 9))));
+        assert_eq!(parse_range("bytes=8-100", 10), Ok(Some((8, 9))));
+        assert_eq!(parse_range("bytes=10-", 10), Err(()));
+        assert_eq!(parse_range("bytes=-0", 10), Err(()));
+        assert_eq!(parse_range("bytes=4-2", 10), Ok(None));
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), Ok(None));
+        assert_eq!(parse_range("items=0-1", 10), Ok(None));
+    }
+
+    #[test]
+    fn serves_files_ranges_and_the_spa_fallback() {
+        let root = std::env::temp_dir().join(format!("static-files-test-{}", std::process::id()));
+        fs::create_dir_all(root.join("assets")).unwrap();
+        fs::write(root.join("index.html"), "<!doctype html>").unwrap();
+        fs::write(root.join("assets/app.js"), "console.log(1)").unwrap();
+        fs::write(root.join(".env"), "SECRET=1").unwrap();
+        let files = StaticFiles::new(root.clone(), "/app", 60);
+
+        let get = |target: &str, headers: &str| {
+            let raw = format!("GET {} HTTP/1.1\r\n{}\r\n", target, headers);
+            files.serve(&Request::from_raw(&raw))
+        };
+
+        let js = get("/app/assets/app.js", "").unwrap();
+        assert_eq!(js.get_header("Content-Type"), Some("application/javascript"));
+        assert_eq!(js.get_header("Cache-Control"), Some("public, max-age=60"));
+        assert_eq!(js.get_body(), b"console.log(1)");
+
+        let spa = get("/app/problems/4", "Accept: text/html,application/xhtml+xml,*/*;q=0.8\r\n").unwrap();
+        assert_eq!(spa.get_body(), b"<!doctype html>");
+        assert_eq!(spa.get_header("Cache-Control"), Some("no-cache"));
+        // API clients mistyping a route get the router's 404, not the app.
+        assert!(get("/app/problems/4", "").is_none());
+        assert!(get("/app/problems/4", "Accept: */*\r\n").is_none());
+        assert!(get("/app/problems/4", "Accept: application/json, text/html;q=0\r\n").is_none());
+
+        let part = get("/app/assets/app.js", "Range: bytes=0-6\r\n").unwrap();
+        assert_eq!(part.get_status(), 206);
+        assert_eq!(part.get_body(), b"console");
+        assert_eq!(part.get_header("Content-Range"), Some("bytes 0-6/14"));
+        assert_eq!(get("/app/assets/app.js", "Range: bytes=20-\r\n").unwrap().get_status(), 416);
+
+        let head = files.serve(&Request::from_raw("HEAD /app/assets/app.js HTTP/1.1\r\nRange: bytes=8-\r\n\r\n")).unwrap();
+        assert_eq!(head.get_status(), 206);
+        let head = String::from_utf8(head.to_bytes()).unwrap();
+        assert!(head.contains("Content-Length: 6\r\n"), "{}", head);
+        assert!(head.ends_with("\r\n\r\n"), "HEAD must not carry a body: {}", head);
+
+        assert!(get("/app/assets/missing.js", "").is_none());
+        assert!(get("/app/../etc/passwd", "").is_none());
+        assert!(get("/app/.env", "").is_none());
+        assert!(get("/application", "").is_none());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,379 +1,470 @@
-use std::collections::HashMap;
-use serde_json::{from_str, json, Value};
-
-use crate::network::{Request, Response, VERSION};
-use crate::models::codehandler::CodeHandler;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use serde_json::{json, Value};
+
+use crate::caching;
+use crate::config::get_config;
+use crate::metrics;
+use crate::pool::get_pool;
+use crate::forms::{self, LoginForm, ProblemForm, RunForm, SignupForm, SolveForm};
+use crate::network::{Request, Response};
+use crate::routes::Params;
+use crate::middleware::Cors;
+use crate::models::codehandler::{CodeHandler, Stage, Verdict, SESSION_TIME_LIMIT};
+use crate::models::submission;
 use crate::models::user::User;
 use crate::models::problem::Problem;
+use crate::websocket::{self, Message, WebSocket, Writer, NORMAL_CLOSURE, UNSUPPORTED_DATA};
 
-pub fn greet(request: &Request) -> Response {
-    Response::new(200, HashMap::new(), format!("Hello, world!\n\n<-- {}{} -->", request.get_header("Host").unwrap(), request.get_path()), String::from(VERSION))
+pub fn greet(request: &Request, _params: &Params) -> Response {
+    Response::text(200, format!("Hello, world!\n\n<-- {}{} -->", request.get_header("Host").unwrap_or(""), request.get_path()))
 }
 
-pub fn not_found(request: &Request) -> Response {
-    let response_body = json!({
-        "message": format!("Not found: {}", request.get_path())
-    });
-    Response::new(404, HashMap::new(), response_body.to_string(), String::from(VERSION))
+pub fn not_found(request: &Request, _params: &Params) -> Response {
+    Response::not_found(format!("Not found: {}", request.get_path()))
 }
 
-pub fn signup(request: &Request) -> Response {
-    let mut data: HashMap<String, Value> = match from_str(request.get_body()) {
-        Ok(json) => json,
-        Err(_) => {
-            let response_body = json!({ "message": "Invalid JSON" });
-            return Response::new(400, HashMap::new(), response_body.to_string(), String::from(VERSION));
-        }
-    };
+pub fn internal_error(_request: &Request, _params: &Params) -> Response {
+    Response::internal_error("Internal server error")
+}
 
-    let username = match data.remove("username") {
-        Some(Value::String(u)) => u,
-        _ => {
-            let response_body = json!({ "message": "Missing or invalid 'username'" });
-            return Response::new(400, HashMap::new(), response_body.to_string(), String::from(VERSION));
-        }
-    };
+pub fn method_not_allowed(request: &Request, _params: &Params) -> Response {
+    Response::error(405, format!("Method {} not allowed for {}", request.get_method(), request.get_path()))
+}
 
-    let password = match data.remove("password") {
-        Some(Value::String(p)) => p,
-        _ => {
-            let response_body = json!({ "message": "Missing or invalid 'password'" });
-            return Response::new(400, HashMap::new(), response_body.to_string(), String::from(VERSION));
-        }
+pub fn signup(request: &Request, _params: &Params) -> Response {
+    let form: SignupForm = match forms::extract(request) {
+        Ok(form) => form,
+        Err(response) => return response,
     };
 
-    let mut user = User::new(username, password);
+    let mut user = User::new(form.username, form.password);
     
     match user.register() {
-        Ok(token) => {
-            let mut headers = HashMap::new();
-            headers.insert("Content-Type".to_string(), "application/json".to_string());
-            let response_body = json!({
-                "message": "Registration successful",
-                "token": token
-            });
-            Response::new(200, headers, response_body.to_string(), String::from(VERSION))
-        },
+        Ok(token) => Response::ok(json!({
+            "message": "Registration successful",
+            "token": token
+        })),
         Err(e) => {
             let status = if e.contains("already taken") { 409 } else { 500 };
-            let response_body = json!({ "message": e });
-            Response::new(status, HashMap::new(), response_body.to_string(), String::from(VERSION))
+            Response::error(status, e)
         }
     }
 }
 
-pub fn login(request: &Request) -> Response {
-    let mut data: HashMap<String, Value> = match from_str(request.get_body()) {
-        Ok(json) => json,
-        Err(_) => {
-            let response_body = json!({ "message": "Invalid JSON" });
-            return Response::new(400, HashMap::new(), response_body.to_string(), String::from(VERSION));
-        }
-    };
-
-    let username = match data.remove("username") {
-        Some(Value::String(u)) => u,
-        _ => {
-            let response_body = json!({ "message": "Missing or invalid 'username'" });
-            return Response::new(400, HashMap::new(), response_body.to_string(), String::from(VERSION));
-        }
-    };
-
-    let password = match data.remove("password") {
-        Some(Value::String(p)) => p,
-        _ => {
-            let response_body = json!({ "message": "Missing or invalid 'password'" });
-            return Response::new(400, HashMap::new(), response_body.to_string(), String::from(VERSION));
-        }
+pub fn login(request: &Request, _params: &Params) -> Response {
+    let form: LoginForm = match forms::extract(request) {
+        Ok(form) => form,
+        Err(response) => return response,
     };
 
-    let user = User::new(username, password);
+    let user = User::new(form.username, form.password);
     
     match user.login() {
-        Ok(token) => {
-            let mut headers = HashMap::new();
-            headers.insert("Content-Type".to_string(), "application/json".to_string());
-            let response_body = json!({
-                "message": "Login successful",
-                "token": token
-            });
-            Response::new(200, headers, response_body.to_string(), String::from(VERSION))
-        },
+        Ok(token) => Response::ok(json!({
+            "message": "Login successful",
+            "token": token
+        })),
         Err(e) => {
             let status = if e.contains("Invalid username or password") { 401 } else { 500 };
-            let response_body = json!({ "message": e });
-            Response::new(status, HashMap::new(), response_body.to_string(), String::from(VERSION))
+            Response::error(status, e)
         }
     }
 }
 
-pub fn ide(request: &Request) -> Response {
-    let mut data: HashMap<String, Value> = match from_str(request.get_body()) {
-        Ok(json) => json,
-        Err(_) => {
-            let response_body = json!({ "message": "Invalid JSON" });
-            return Response::new(400, HashMap::new(), response_body.to_string(), VERSION.into());
-        }
-    };
-
-    let code = match data.remove("code") {
-        Some(Value::String(s)) => s,
-        _ => {
-            let response_body = json!({ "message": "Missing or invalid 'code'" });
-            return Response::new(400, HashMap::new(), response_body.to_string(), VERSION.into());
-        }
-    };
-
-    let language = match data.remove("language") {
-        Some(Value::String(s)) => s.to_lowercase(),
-        _ => {
-            let response_body = json!({ "message": "Missing or invalid 'language'" });
-            return Response::new(400, HashMap::new(), response_body.to_string(), VERSION.into());
-        }
+pub fn ide(request: &Request, _params: &Params) -> Response {
+    let form: RunForm = match forms::extract(request) {
+        Ok(form) => form,
+        Err(response) => return response,
     };
 
-    let input = match data.remove("input") {
-        Some(Value::String(i)) => i,
-        _ => String::new(),
-    };
-
-    let mut handler = CodeHandler::new(code, language);
-    
-    handler.use_input(input);
-    handler.execute();
+    let mut handler = CodeHandler::new(form.code, form.language.to_lowercase());
+    handler.use_input(form.input);
+    let _ = handler.execute();
 
-    let response_body = json!({
+    Response::ok(json!({
         "message": if handler.get_error().is_empty() { "Execution successful" } else { "Execution failed" },
         "output": handler.get_output(),
         "error": handler.get_error(),
         "runtime": handler.get_runtime(),
         "memory": handler.get_memory(),
-    });
+    }))
+}
 
-    let mut headers = HashMap::new();
-    headers.insert("Content-Type".into(), "application/json".into());
+/// Runs code interactively over a WebSocket.
+///
+/// The first message is the code and language, as for `/ide`. After that the
+/// client sends `{"type":"stdin","data":...}` and `{"type":"eof"}`, and gets
+/// `compiling`, `running`, `stdout` and `stderr` messages back, then `exit`
+/// or `error` before the server closes the session.
+pub fn ide_ws(request: &Request, _params: &Params) -> Response {
+    // Browsers don't apply CORS to WebSockets, so any page could open one.
+    if let Some(origin) = request.get_header("Origin")
+        && !Cors::from_config().allows_origin(origin)
+    {
+        return Response::error(403, format!("Origin {} is not allowed", origin));
+    }
+    websocket::upgrade(request, interactive_session)
+}
 
-    Response::new(200, headers, response_body.to_string(), VERSION.into())
+fn send(writer: &Writer, message: Value) {
+    // A failed send means the client is gone; reading will tell us as much.
+    let _ = writer.send_text(&message.to_string());
 }
 
-pub fn add_problem(request: &Request) -> Response {
-    let mut data: HashMap<String, Value> = match from_str(request.get_body()) {
-        Ok(json) => json,
-        Err(_) => {
-            let response_body = json!({ "message": "Invalid JSON" });
-            return Response::new(400, HashMap::new(), response_body.to_string(), VERSION.into());
+/// Forwards one of a program's output streams as `stdout` or `stderr`
+/// messages until it closes. A character split across reads is held back
+/// until the rest of it arrives.
+fn pump(mut stream: impl Read, name: &str, writer: &Writer) {
+    let mut buffer = [0u8; 4096];
+    let mut pending = Vec::new();
+
+    loop {
+        let n = match stream.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        pending.extend_from_slice(&buffer[..n]);
+        let complete = match std::str::from_utf8(&pending) {
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            _ => pending.len(),
+        };
+        if complete > 0 {
+            let data = String::from_utf8_lossy(&pending[..complete]).into_owned();
+            pending.drain(..complete);
+            send(writer, json!({ "type": name, "data": data }));
         }
-    };
+    }
+    if !pending.is_empty() {
+        send(writer, json!({ "type": name, "data": String::from_utf8_lossy(&pending) }));
+    }
+}
 
-    let token = match request.get_header("Authorization") {
-        Some(t) => t.split_whitespace().nth(1).unwrap_or(""),
-        None => {
-            let response_body = json!({ "message": "Missing or invalid 'Authorization' header" });
-            return Response::new(401, HashMap::new(), response_body.to_string(), VERSION.into());
-        }
-    };
+fn interactive_session(mut socket: WebSocket) {
+    let writer = socket.writer();
 
-    let creator = match User::get_username_from_jwt(&token) {
-        Ok(username) => username,
-        Err(_) => {
-            let response_body = json!({ "message": "Invalid token" });
-            return Response::new(401, HashMap::new(), response_body.to_string(), VERSION.into());
+    // However the session goes, the connection is dropped past the time limit.
+    let (_session, ended) = mpsc::channel::<()>();
+    let watchdog = socket.writer();
+    thread::spawn(move || {
+        if ended.recv_timeout(Duration::from_secs(SESSION_TIME_LIMIT + 10)) == Err(RecvTimeoutError::Timeout) {
+            watchdog.abort();
         }
+    });
+
+    let form: RunForm = match socket.recv() {
+        Ok(Message::Text(text)) => match forms::from_json(&text) {
+            Ok(form) => form,
+            Err(response) => {
+                let mut error: Value = serde_json::from_slice(response.get_body()).unwrap_or_default();
+                error["type"] = Value::from("error");
+                send(&writer, error);
+                let _ = writer.close(NORMAL_CLOSURE, "");
+                return;
+            },
+        },
+        Ok(Message::Binary(_)) => {
+            let _ = writer.close(UNSUPPORTED_DATA, "Expected the code as JSON text");
+            return;
+        },
+        Ok(Message::Close(_)) | Err(_) => return,
     };
 
-    let title = match data.remove("title") {
-        Some(Value::String(s)) => s,
-        _ => {
-            let response_body = json!({ "message": "Missing or invalid 'title'" });
-            return Response::new(400, HashMap::new(), response_body.to_string(), VERSION.into());
+    let mut handler = CodeHandler::new(form.code, form.language.to_lowercase());
+    let progress = socket.writer();
+    handler.on_progress(move |stage| {
+        if stage == Stage::Compiling {
+            send(&progress, json!({ "type": "compiling" }));
         }
+    });
+    let run = match handler.start_interactive() {
+        Ok(run) => Arc::new(run),
+        Err(e) => {
+            send(&writer, json!({ "type": "error", "message": e }));
+            let _ = writer.close(NORMAL_CLOSURE, "");
+            return;
+        },
     };
+    send(&writer, json!({ "type": "running" }));
 
-    let description = match data.remove("description") {
-        Some(Value::String(s)) => s,
-        _ => {
-            let response_body = json!({ "message": "Missing or invalid 'description'" });
-            return Response::new(400, HashMap::new(), response_body.to_string(), VERSION.into());
+    let mut pumps = Vec::new();
+    if let Some(stdout) = run.take_stdout() {
+        let writer = socket.writer();
+        pumps.push(thread::spawn(move || pump(stdout, "stdout", &writer)));
+    }
+    if let Some(stderr) = run.take_stderr() {
+        let writer = socket.writer();
+        pumps.push(thread::spawn(move || pump(stderr, "stderr", &writer)));
+    }
+
+    // Once the program's output closes it has ended: report how, then close.
+    let waiter = Arc::clone(&run);
+    thread::spawn(move || {
+        for pump in pumps {
+            let _ = pump.join();
         }
-    };
+        let code = waiter.wait().ok().and_then(|status| status.code());
+        let message = match code {
+            Some(0) => "Execution successful",
+            // Killed by `timeout`, or by the CPU limit.
+            Some(124 | 137 | 152) | None => "Time Limit Exceeded",
+            Some(_) => "Runtime Error",
+        };
+        send(&writer, json!({ "type": "exit", "code": code, "message": message, "runtime": waiter.runtime() }));
+        let _ = writer.close(NORMAL_CLOSURE, "");
+    });
 
-    let input = match data.remove("input") {
-        Some(Value::String(s)) => s,
-        _ => {
-            let response_body = json!({ "message": "Missing or invalid 'input'" });
-            return Response::new(400, HashMap::new(), response_body.to_string(), VERSION.into());
+    let mut stdin = run.take_stdin();
+    loop {
+        let input = match socket.recv() {
+            Ok(Message::Text(text)) => {
+                let message: Value = serde_json::from_str(&text).unwrap_or_default();
+                match (message["type"].as_str(), message["data"].as_str()) {
+                    (Some("stdin"), Some(data)) => data.as_bytes().to_vec(),
+                    (Some("eof"), _) => {
+                        stdin = None;
+                        continue;
+                    },
+                    _ => {
+                        send(&socket.writer(), json!({ "type": "error", "message": "Expected a 'stdin' or 'eof' message" }));
+                        continue;
+                    },
+                }
+            },
+            Ok(Message::Binary(data)) => data,
+            Ok(Message::Close(_)) | Err(_) => break,
+        };
+        // The program may have stopped reading; the rest of its input is dropped.
+        if let Some(pipe) = stdin.as_mut()
+            && pipe.write_all(&input).and_then(|_| pipe.flush()).is_err()
+        {
+            stdin = None;
         }
+    }
+    run.kill();
+}
+
+pub fn add_problem(request: &Request, _params: &Params) -> Response {
+    let creator = match request.get_user() {
+        Some(username) => username.clone(),
+        None => return Response::unauthorized("Missing or invalid 'Authorization' header"),
     };
 
-    let output = match data.remove("output") {
-        Some(Value::String(s)) => s,
-        _ => {
-            let response_body = json!({ "message": "Missing or invalid 'output'" });
-            return Response::new(400, HashMap::new(), response_body.to_string(), VERSION.into());
-        }
+    let form: ProblemForm = match forms::extract(request) {
+        Ok(form) => form,
+        Err(response) => return response,
     };
 
-    let mut problem = Problem::new(creator, title, description, input, output);
+    let mut problem = Problem::new(creator, form.title, form.description, form.input, form.output);
     
     match problem.save() {
-        Ok(_) => {
-            let mut headers = HashMap::new();
-            headers.insert("Content-Type".into(), "application/json".into());
-            let response_body = json!({
-                "message": "Problem added successfully",
-                "id": problem.id
-            });
-            Response::new(201, headers, response_body.to_string(), VERSION.into())
-        },
-        Err(e) => {
-            let response_body = json!({ "message": e });
-            Response::new(500, HashMap::new(), response_body.to_string(), VERSION.into())
-        }
+        Ok(_) => Response::created(json!({
+            "message": "Problem added successfully",
+            "id": problem.id
+        })),
+        Err(e) => Response::internal_error(e),
     }
 }
 
-pub fn get_all_problems(_request: &Request) -> Response {
-    match Problem::get_all() {
+pub fn get_all_problems(request: &Request, _params: &Params) -> Response {
+    let problems = match request.get_query::<u64>("page") {
+        Some(page) if page > 0 => {
+            let per_page = request.get_query::<u64>("per_page").unwrap_or(20).clamp(1, 100);
+            Problem::get_page(page, per_page)
+        },
+        _ => Problem::get_all(),
+    };
+
+    match problems {
         Ok(problems) => {
-            let mut headers = HashMap::new();
-            headers.insert("Content-Type".into(), "application/json".into());
-            let response_body = json!({
+            let last_modified = problems.iter().filter_map(|p| p.updated_at).max();
+            let response = Response::ok(json!({
                 "message": "Problems retrieved successfully",
                 "problems": problems,
                 "count": problems.len()
-            });
-            Response::new(200, headers, response_body.to_string(), VERSION.into())
+            }));
+            caching::conditional(request, response, last_modified)
         },
-        Err(e) => {
-            let response_body = json!({ "message": e });
-            Response::new(500, HashMap::new(), response_body.to_string(), VERSION.into())
-        }
+        Err(e) => Response::internal_error(e),
     }
 }
 
-pub fn get_problem_by_id(request: &Request, id: u64) -> Response {
+pub fn get_problem_by_id(request: &Request, params: &Params) -> Response {
+    let Some(id) = params.get::<u64>("id") else {
+        return not_found(request, params);
+    };
+
     match Problem::find_by_id(id) {
         Ok(Some(problem)) => {
-            let mut headers = HashMap::new();
-            headers.insert("Content-Type".into(), "application/json".into());
-            let response_body = json!({
+            let last_modified = problem.updated_at;
+            let response = Response::ok(json!({
                 "message": "Problem retrieved successfully",
                 "problem": problem
-            });
-            Response::new(200, headers, response_body.to_string(), VERSION.into())
-        },
-        Ok(None) => {
-            let response_body = json!({
-                "message": format!("Problem with id {} not found", id)
-            });
-            Response::new(404, HashMap::new(), response_body.to_string(), VERSION.into())
+            }));
+            caching::conditional(request, response, last_modified)
         },
-        Err(e) => {
-            let response_body = json!({ "message": e });
-            Response::new(500, HashMap::new(), response_body.to_string(), VERSION.into())
-        }
+        Ok(None) => Response::not_found(format!("Problem with id {} not found", id)),
+        Err(e) => Response::internal_error(e),
     }
 }
 
-pub fn solve_problem(request: &Request, problem_id: u64) -> Response {
-    let token = match request.get_header("Authorization") {
-        Some(t) => t.split_whitespace().nth(1).unwrap_or(""),
-        None => {
-            let response_body = json!({ "message": "Missing or invalid 'Authorization' header" });
-            return Response::new(401, HashMap::new(), response_body.to_string(), VERSION.into());
-        }
+pub fn solve_problem(request: &Request, params: &Params) -> Response {
+    let Some(problem_id) = params.get::<u64>("id") else {
+        return not_found(request, params);
     };
 
-    let username = match User::get_username_from_jwt(token) {
-        Ok(u) => u,
-        Err(e) => {
-            let response_body = json!({ "message": e });
-            return Response::new(401, HashMap::new(), response_body.to_string(), VERSION.into());
-        }
+    let username = match request.get_user() {
+        Some(username) => username.clone(),
+        None => return Response::unauthorized("Missing or invalid 'Authorization' header"),
     };
 
-    let mut data: HashMap<String, Value> = match from_str(request.get_body()) {
-        Ok(json) => json,
-        Err(_) => {
-            let response_body = json!({ "message": "Invalid JSON" });
-            return Response::new(400, HashMap::new(), response_body.to_string(), VERSION.into());
-        }
+    let form: SolveForm = match forms::extract(request) {
+        Ok(form) => form,
+        Err(response) => return response,
     };
 
-    let code = match data.remove("code") {
-        Some(Value::String(s)) => s,
-        _ => {
-            let response_body = json!({ "message": "Missing or invalid 'code'" });
-            return Response::new(400, HashMap::new(), response_body.to_string(), VERSION.into());
-        }
+    let problem = match Problem::find_by_id(problem_id) {
+        Ok(Some(p)) => p,
+        Ok(None) => return Response::not_found(format!("Problem {} not found", problem_id)),
+        Err(e) => return Response::internal_error(e),
     };
 
-    let language = match data.remove("language") {
-        Some(Value::String(s)) => s.to_lowercase(),
-        _ => {
-            let response_body = json!({ "message": "Missing or invalid 'language'" });
-            return Response::new(400, HashMap::new(), response_body.to_string(), VERSION.into());
+    let submission_id = submission::create(json!({ "problem_id": problem_id }));
+
+    // `?async` answers straight away; the verdict then arrives as an event.
+    // Judging still takes a worker, so it counts against the queue limit and
+    // shutdown waits for it.
+    if request.get_query_raw("async").is_some() {
+        let unfinished = Unfinished(submission_id.clone());
+        let job = move || {
+            judge(&unfinished.0, problem_id, problem, username, form);
+        };
+        if get_pool().execute(job).is_err() {
+            return Response::error(503, "Server is busy, try again later");
         }
-    };
+        let events = format!("/submissions/{}/events", submission_id);
+        return Response::json(202, json!({ "submission_id": submission_id, "events": events }))
+            .with_header("Location", &events);
+    }
 
-    let problem = match Problem::find_by_id(problem_id) {
-        Ok(Some(p)) => p,
-        Ok(None) => {
-            let response_body = json!({ "message": format!("Problem {} not found", problem_id) });
-            return Response::new(404, HashMap::new(), response_body.to_string(), VERSION.into());
-        }
-        Err(e) => {
-            let response_body = json!({ "message": e });
-            return Response::new(500, HashMap::new(), response_body.to_string(), VERSION.into());
-        }
-    };
+    let _unfinished = Unfinished(submission_id.clone());
+    let (status, mut body) = judge(&submission_id, problem_id, problem, username, form);
+    body["submission_id"] = Value::from(submission_id);
+    Response::json(status, body)
+}
+
+/// Ends a submission's event stream with an `error` if it's dropped before a
+/// verdict, so watchers aren't left waiting on a job that panicked or never ran.
+struct Unfinished(String);
 
+impl Drop for Unfinished {
+    fn drop(&mut self) {
+        submission::finish(&self.0, "error", json!({ "message": "Judging failed" }));
+    }
+}
+
+/// Runs a submission against its problem, publishing its progress as events,
+/// and returns the verdict as a status and JSON body.
+fn judge(submission_id: &str, problem_id: u64, problem: Problem, username: String, form: SolveForm) -> (u16, Value) {
     if let Err(e) = Problem::increment_tried(problem_id) {
-        let response_body = json!({ "message": e });
-        return Response::new(500, HashMap::new(), response_body.to_string(), VERSION.into());
+        submission::finish(submission_id, "error", json!({ "message": e }));
+        return (500, json!({ "message": e }));
     }
 
-    let mut handler = CodeHandler::new(code, language);
+    let mut handler = CodeHandler::new(form.code, form.language.to_lowercase());
     handler.use_input(problem.input.clone());
-    handler.execute();
-
-    if handler.get_output().trim() == problem.output.trim() {
+    let id = submission_id.to_string();
+    handler.on_progress(move |stage| match stage {
+        Stage::Compiling => submission::publish(&id, "compiling", json!({})),
+        Stage::Running { test } => submission::publish(&id, "running", json!({ "test": test })),
+    });
+    let accepted = handler.judge(&problem.output) == Verdict::Accepted;
+    if accepted {
         let mut user = User::new(username, String::new());
-        if let Err(e) = user.new_solve(problem_id) {
-            let response_body = json!({ "message": e });
-            return Response::new(500, HashMap::new(), response_body.to_string(), VERSION.into());
-        }
-
-        if let Err(e) = Problem::increment_solved(problem_id) {
-            let response_body = json!({ "message": e });
-            return Response::new(500, HashMap::new(), response_body.to_string(), VERSION.into());
+        let recorded = user.new_solve(problem_id).and_then(|_| Problem::increment_solved(problem_id));
+        if let Err(e) = recorded {
+            submission::finish(submission_id, "error", json!({ "message": e }));
+            return (500, json!({ "message": e }));
         }
+    }
 
-        let mut headers = HashMap::new();
-        headers.insert("Content-Type".into(), "application/json".into());
-        let response_body = json!({
+    let (status, verdict) = if accepted {
+        (200, json!({
             "message": "Problem solved successfully!",
             "output": handler.get_output(),
             "runtime": handler.get_runtime(),
             "memory": handler.get_memory()
-        });
-        Response::new(200, headers, response_body.to_string(), VERSION.into())
+        }))
     } else {
-        let response_body = json!({
+        (400, json!({
             "message": "Wrong answer",
             "output": handler.get_output(),
             "error": handler.get_error(),
             "runtime": handler.get_runtime(),
             "memory": handler.get_memory()
-        });
-        Response::new(400, HashMap::new(), response_body.to_string(), VERSION.into())
+        }))
+    };
+
+    let mut event = verdict.clone();
+    event["accepted"] = Value::from(accepted);
+    submission::finish(submission_id, "verdict", event);
+    (status, verdict)
+}
+
+/// Streams a submission's progress, `queued` through `verdict`, as server-sent
+/// events. A reconnecting client resumes after its `Last-Event-ID`.
+pub fn submission_events(request: &Request, params: &Params) -> Response {
+    let Some(id) = params.raw("id") else {
+        return not_found(request, params);
+    };
+    let last_event_id = request.get_header("Last-Event-ID").and_then(|id| id.trim().parse().ok());
+
+    match submission::subscribe(id, last_event_id) {
+        Some(events) => Response::event_stream(events),
+        None => Response::not_found(format!("Submission {} not found", id)),
+    }
+}
+
+pub fn get_user_solves(request: &Request, params: &Params) -> Response {
+    let Some(username) = params.raw("name") else {
+        return not_found(request, params);
+    };
+
+    match User::get_user_by_username(username) {
+        Ok(user) => Response::ok(json!({
+            "message": "Solves retrieved successfully",
+            "username": user.username,
+            "solves": user.get_solves(),
+            "count": user.get_solves().len()
+        })),
+        Err(e) => {
+            let status = if e.contains("User not found") { 404 } else { 500 };
+            Response::error(status, e)
+        }
+    }
+}
+
+/// Compares tokens in time independent of where they first differ.
+fn token_matches(given: &str, expected: &str) -> bool {
+    given.len() == expected.len() && given.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Every metric in the Prometheus text format. With `METRICS_TOKEN` set,
+/// scrapers must send it as a bearer token.
+pub fn metrics(request: &Request, _params: &Params) -> Response {
+    if let Some(token) = &get_config().metrics_token {
+        let given = request.get_header("Authorization").and_then(|value| value.trim().strip_prefix("Bearer "));
+        if !given.is_some_and(|given| token_matches(given.trim(), token)) {
+            return Response::unauthorized("Missing or invalid metrics token").with_header("WWW-Authenticate", "Bearer");
+        }
     }
+    Response::bytes(200, "text/plain; version=0.0.4; charset=utf-8", metrics::render().into_bytes())
 }
 
-pub fn handle_options(_request: &Request) -> Response {
-    let mut headers = HashMap::new();
-    headers.insert("Content-Type".to_string(), "text/plain".to_string());
-    let response_body = json!({ "message": "Options request successful" });
-    Response::new(204, headers, response_body.to_string(), VERSION.to_string())
+pub fn handle_options(_request: &Request, _params: &Params) -> Response {
+    Response::empty(204)
 }
 
@@ -0,0 +1,388 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use sha1::{Digest, Sha1};
+
+use crate::config::get_config;
+use crate::network::{Request, Response};
+use crate::server::Socket;
+
+/// Appended to the client's key before hashing, per RFC 6455.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Largest message we take from a client. Keystrokes and pastes are small.
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xA;
+
+pub const NORMAL_CLOSURE: u16 = 1000;
+const GOING_AWAY: u16 = 1001;
+const PROTOCOL_ERROR: u16 = 1002;
+pub const UNSUPPORTED_DATA: u16 = 1003;
+const INVALID_PAYLOAD: u16 = 1007;
+const MESSAGE_TOO_BIG: u16 = 1009;
+
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// The client closed the session, with a status code if it gave one.
+    Close(Option<u16>),
+}
+
+/// `Sec-WebSocket-Accept` for a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.trim().as_bytes());
+    hasher.update(GUID.as_bytes());
+    BASE64.encode(hasher.finalize())
+}
+
+/// Sessions that were accepted, by id, with their writer once the connection
+/// has been handed over.
+struct Sessions {
+    open: HashMap<u64, Option<Writer>>,
+    /// Set on shutdown; no new sessions are accepted after that.
+    closing: bool,
+}
+
+static SESSIONS: Mutex<Option<Sessions>> = Mutex::new(None);
+static NEXT_SESSION: AtomicU64 = AtomicU64::new(0);
+
+fn with_sessions<T>(f: impl FnOnce(&mut Sessions) -> T) -> T {
+    let mut sessions = SESSIONS.lock().unwrap_or_else(|e| e.into_inner());
+    f(sessions.get_or_insert_with(|| Sessions { open: HashMap::new(), closing: false }))
+}
+
+/// A session's place among the open ones, given up when dropped.
+struct Slot(u64);
+
+impl Slot {
+    /// `None` when `MAX_SESSIONS` are already open or the server is shutting down.
+    fn reserve() -> Option<Slot> {
+        with_sessions(|sessions| {
+            if sessions.closing || sessions.open.len() >= get_config().max_sessions {
+                return None;
+            }
+            let id = NEXT_SESSION.fetch_add(1, Ordering::Relaxed);
+            sessions.open.insert(id, None);
+            Some(Slot(id))
+        })
+    }
+
+    /// Makes the session reachable by `close_all`, or closes it right away
+    /// if shutdown began while it was being handed over.
+    fn register(&self, writer: &Writer) {
+        let closing = with_sessions(|sessions| {
+            sessions.open.insert(self.0, Some(writer.clone()));
+            sessions.closing
+        });
+        if closing {
+            let _ = writer.close(GOING_AWAY, "Server shutting down");
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        with_sessions(|sessions| sessions.open.remove(&self.0));
+    }
+}
+
+/// Sessions accepted and not yet ended.
+pub fn open_sessions() -> usize {
+    with_sessions(|sessions| sessions.open.len())
+}
+
+/// Refuses new sessions and starts the closing handshake on every open one,
+/// each on a thread of its own since a slow client can block the send.
+pub fn close_all() {
+    let writers: Vec<Writer> = with_sessions(|sessions| {
+        sessions.closing = true;
+        sessions.open.values().flatten().cloned().collect()
+    });
+    for writer in writers {
+        std::thread::spawn(move || {
+            let _ = writer.close(GOING_AWAY, "Server shutting down");
+        });
+    }
+}
+
+/// Drops the connection of every session still open.
+pub fn abort_all() {
+    let writers: Vec<Writer> = with_sessions(|sessions| sessions.open.values().flatten().cloned().collect());
+    for writer in writers {
+        writer.abort();
+    }
+}
+
+/// Answers a WebSocket opening handshake and runs `session` on the upgraded
+/// connection. Anything that isn't a valid handshake gets an error response.
+pub fn upgrade(request: &Request, session: impl FnOnce(WebSocket) + Send + 'static) -> Response {
+    let headers = request.get_headers();
+    if !headers.has_token("Upgrade", "websocket") || !headers.has_token("Connection", "upgrade") {
+        return Response::error(426, "Expected a WebSocket upgrade").with_header("Upgrade", "websocket");
+    }
+    if request.get_header("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        return Response::error(426, "Unsupported WebSocket version").with_header("Sec-WebSocket-Version", "13");
+    }
+    let key = match request.get_header("Sec-WebSocket-Key") {
+        Some(key) if BASE64.decode(key.trim()).is_ok_and(|nonce| nonce.len() == 16) => key,
+        _ => return Response::bad_request("Missing or invalid 'Sec-WebSocket-Key' header"),
+    };
+
+    let Some(slot) = Slot::reserve() else {
+        return Response::error(503, "Too many open sessions, try again later");
+    };
+
+    // The slot goes with the closure, so it's freed whether or not the
+    // connection is ever handed over.
+    Response::switching_protocols("websocket", move |socket| match WebSocket::new(socket) {
+        Ok(ws) => {
+            slot.register(&ws.writer);
+            session(ws);
+        },
+        Err(e) => crate::logging::error(&format!("Failed to start WebSocket session: {}", e)),
+    })
+    .with_header("Sec-WebSocket-Accept", &accept_key(key))
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Reads one client frame. Clients must mask every frame; we don't use extensions.
+fn read_frame(reader: &mut impl Read) -> io::Result<Frame> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head)?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0F;
+    if head[0] & 0x70 != 0 {
+        return Err(invalid("reserved bits set"));
+    }
+    if head[1] & 0x80 == 0 {
+        return Err(invalid("unmasked client frame"));
+    }
+
+    let len = match head[1] & 0x7F {
+        126 => {
+            let mut len = [0u8; 2];
+            reader.read_exact(&mut len)?;
+            u64::from(u16::from_be_bytes(len))
+        },
+        127 => {
+            let mut len = [0u8; 8];
+            reader.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        },
+        len => u64::from(len),
+    };
+    if opcode >= CLOSE && (len > 125 || !fin) {
+        return Err(invalid("malformed control frame"));
+    }
+    if len > MAX_MESSAGE_SIZE as u64 {
+        return Err(io::Error::new(io::ErrorKind::FileTooLarge, "frame too large"));
+    }
+
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask)?;
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Frame { fin, opcode, payload })
+}
+
+/// A single unfragmented, unmasked server frame.
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        },
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        },
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// The sending half of a session. Clones share the connection, so several
+/// threads can send; frames never interleave.
+#[derive(Clone)]
+pub struct Writer {
+    /// The socket, and whether we've sent a close frame on it.
+    inner: Arc<Mutex<(Socket, bool)>>,
+    /// Kept apart so aborting doesn't wait on a send stuck behind a slow client.
+    control: Arc<Socket>,
+}
+
+impl Writer {
+    fn send_frame(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut guard = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let (socket, closed) = &mut *guard;
+        // Nothing may follow a close frame.
+        if *closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        *closed = opcode == CLOSE;
+        socket.write_all(&encode_frame(opcode, payload))
+    }
+
+    pub fn send_text(&self, text: &str) -> io::Result<()> {
+        self.send_frame(TEXT, text.as_bytes())
+    }
+
+    /// Starts the closing handshake; the client answers with a close of its own.
+    pub fn close(&self, code: u16, reason: &str) -> io::Result<()> {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(&reason.as_bytes()[..reason.len().min(123)]);
+        self.send_frame(CLOSE, &payload)
+    }
+
+    /// Drops the connection without a closing handshake, waking up whoever
+    /// is blocked reading from it.
+    pub fn abort(&self) {
+        self.control.shutdown();
+    }
+}
+
+/// A WebSocket session on an upgraded connection.
+pub struct WebSocket {
+    reader: Socket,
+    writer: Writer,
+}
+
+impl WebSocket {
+    fn new(socket: Socket) -> io::Result<WebSocket> {
+        let writer = Writer {
+            inner: Arc::new(Mutex::new((socket.try_clone()?, false))),
+            control: Arc::new(socket.try_clone()?),
+        };
+        Ok(WebSocket { reader: socket, writer })
+    }
+
+    pub fn writer(&self) -> Writer {
+        self.writer.clone()
+    }
+
+    /// Waits for the next message, answering pings and reassembling fragments
+    /// on the way. A client breaking the protocol is sent a close and an error
+    /// is returned.
+    pub fn recv(&mut self) -> io::Result<Message> {
+        let mut message: Option<(u8, Vec<u8>)> = None;
+
+        loop {
+            let frame = match read_frame(&mut self.reader) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    let code = match e.kind() {
+                        io::ErrorKind::InvalidData => PROTOCOL_ERROR,
+                        io::ErrorKind::FileTooLarge => MESSAGE_TOO_BIG,
+                        _ => return Err(e),
+                    };
+                    let _ = self.writer.close(code, &e.to_string());
+                    return Err(e);
+                },
+            };
+
+            match (frame.opcode, &mut message) {
+                (PING, _) => {
+                    let _ = self.writer.send_frame(PONG, &frame.payload);
+                    continue;
+                },
+                (PONG, _) => continue,
+                (CLOSE, _) => {
+                    let code = frame.payload.get(..2).map(|code| u16::from_be_bytes([code[0], code[1]]));
+                    // Echo the client's code to complete the closing handshake.
+                    let _ = self.writer.close(code.unwrap_or(NORMAL_CLOSURE), "");
+                    return Ok(Message::Close(code));
+                },
+                (TEXT | BINARY, None) => message = Some((frame.opcode, frame.payload)),
+                (CONTINUATION, Some((_, data))) => {
+                    if data.len() + frame.payload.len() > MAX_MESSAGE_SIZE {
+                        let _ = self.writer.close(MESSAGE_TOO_BIG, "message too large");
+                        return Err(io::Error::new(io::ErrorKind::FileTooLarge, "message too large"));
+                    }
+                    data.extend_from_slice(&frame.payload);
+                },
+                _ => {
+                    let _ = self.writer.close(PROTOCOL_ERROR, "unexpected frame");
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected frame"));
+                },
+            }
+
+            if !frame.fin {
+                continue;
+            }
+            return match message.take() {
+                Some((TEXT, data)) => match String::from_utf8(data) {
+                    Ok(text) => Ok(Message::Text(text)),
+                    Err(_) => {
+                        let _ = self.writer.close(INVALID_PAYLOAD, "invalid UTF-8");
+                        Err(io::Error::new(io::ErrorKind::InvalidData, "invalid UTF-8 in text message"))
+                    },
+                },
+                Some((_, data)) => Ok(Message::Binary(data)),
+                None => continue,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn masked(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![first, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn computes_the_accept_key() {
+        // The example from RFC 6455, section 1.3.
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn reads_masked_frames() {
+        let mut input = Cursor::new(masked(0x81, b"Hello"));
+        let frame = read_frame(&mut input).unwrap();
+        assert!(frame.fin);
+        assert_eq!((frame.opcode, frame.payload.as_slice()), (TEXT, &b"Hello"[..]));
+
+        let mut unmasked = Cursor::new(vec![0x81, 0x02, b'h', b'i']);
+        assert_eq!(read_frame(&mut unmasked).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        let mut fragmented_ping = Cursor::new(masked(PING, b""));
+        assert_eq!(read_frame(&mut fragmented_ping).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn encodes_lengths() {
+        assert_eq!(encode_frame(TEXT, b"hi"), [0x81, 2, b'h', b'i']);
+        assert_eq!(encode_frame(BINARY, &[0; 300])[..4], [0x82, 126, 0x01, 0x2C]);
+        assert_eq!(encode_frame(BINARY, &[0; 70000])[..10], [0x82, 127, 0, 0, 0, 0, 0, 0x01, 0x11, 0x70]);
+    }
+}